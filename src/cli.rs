@@ -0,0 +1,402 @@
+use crate::{
+    error::{AppError, Result},
+    models::{post::Post, user::Users},
+    routes::files::ALLOWED_FILES,
+    storage::{check_id, fs::FsUserStore, PostStore, UserStore},
+};
+use clap::{Parser, Subcommand};
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::{fs, sync::RwLock};
+
+#[derive(Parser)]
+#[command(about = "Publish markdown documents from Obsidian")]
+pub struct Cli {
+    /// Overrides MOON_DATA_DIR
+    #[arg(long, global = true)]
+    pub data_dir: Option<PathBuf>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Start the server (the default)
+    Serve {
+        /// Overrides MOON_BIND_ADDR
+        #[arg(long)]
+        bind: Option<String>,
+        /// Overrides MOON_PORT
+        #[arg(long)]
+        port: Option<u16>,
+    },
+    /// Manage users
+    #[command(subcommand)]
+    User(UserCommand),
+    /// Manage API keys
+    #[command(subcommand)]
+    Key(KeyCommand),
+    /// Manage posts
+    #[command(subcommand)]
+    Post(PostCommand),
+    /// Write users, posts and templates to a directory
+    Export { dir: PathBuf },
+    /// Read users, posts and templates written by `export`
+    Import { dir: PathBuf },
+}
+
+#[derive(Subcommand)]
+pub enum UserCommand {
+    /// Create a user and print their `default` key
+    Add { username: String },
+    /// List users and their keys
+    List,
+    /// Delete a user and their keys, leaving their posts in place
+    Delete { username: String },
+    /// Set the password for browser logins, read from standard input
+    Password {
+        username: String,
+        /// Remove the password instead
+        #[arg(long)]
+        clear: bool,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum KeyCommand {
+    /// Replace a key with a new one with the same scopes and print it
+    Rotate { username: String, name: String },
+    /// Revoke a key
+    Revoke { username: String, name: String },
+}
+
+#[derive(Subcommand)]
+pub enum PostCommand {
+    /// List a user's posts
+    List { username: String },
+    /// Move a post to the trash
+    Delete {
+        username: String,
+        id: String,
+        /// Delete the post permanently instead
+        #[arg(long)]
+        purge: bool,
+    },
+}
+
+fn internal(e: impl ToString) -> AppError {
+    AppError::Internal(e.to_string())
+}
+
+/// Runs every command except `serve`
+pub async fn run(
+    command: Command,
+    data_dir: &Path,
+    posts: Arc<dyn PostStore>,
+    users: Arc<RwLock<Users>>,
+) -> Result<()> {
+    let mut users = users.write().await;
+    match command {
+        Command::Serve { .. } => unreachable!("serve is handled by main"),
+        Command::User(UserCommand::Add { username }) => {
+            let key = users.create_user(&username).await?;
+            println!("Created user {} with API key {}", username, key);
+        }
+        Command::User(UserCommand::List) => {
+            let mut usernames: Vec<&String> = users.users.keys().collect();
+            usernames.sort();
+            for username in usernames {
+                let user = &users.users[username];
+                let status = if user.disabled { " (disabled)" } else { "" };
+                println!("{}{}", username, status);
+                for key in user.key_summaries() {
+                    let scopes: Vec<String> = key.scopes.iter().map(|s| s.to_string()).collect();
+                    println!("  {} {}", key.name, scopes.join(","));
+                }
+            }
+        }
+        Command::User(UserCommand::Delete { username }) => {
+            users.delete_user(&username).await?;
+            println!("Deleted user {}", username);
+        }
+        Command::User(UserCommand::Password { username, clear }) => {
+            if clear {
+                users.set_password(&username, None).await?;
+                println!("Removed the password of {}", username);
+            } else {
+                let mut password = String::new();
+                std::io::stdin()
+                    .read_line(&mut password)
+                    .map_err(internal)?;
+                let password = password.trim_end_matches(['\r', '\n']);
+                users.set_password(&username, Some(password)).await?;
+                println!("Set the password of {}", username);
+            }
+        }
+        Command::Key(KeyCommand::Rotate { username, name }) => {
+            let key = users.rotate_key(&username, &name).await?;
+            println!("New API key for {} {}: {}", username, name, key);
+        }
+        Command::Key(KeyCommand::Revoke { username, name }) => {
+            users.revoke_key(&username, &name).await?;
+            println!("Revoked key {} of {}", name, username);
+        }
+        Command::Post(PostCommand::List { username }) => {
+            for post in posts.list(&username).await? {
+                println!("{}\t{}\t{}", post.id, post.path, post.name);
+            }
+        }
+        Command::Post(PostCommand::Delete {
+            username,
+            id,
+            purge,
+        }) => {
+            posts.delete(&username, &id).await?;
+            if purge {
+                posts.purge_trashed(&username, &id).await?;
+                println!("Deleted {}", id);
+            } else {
+                println!("Moved {} to the trash", id);
+            }
+        }
+        Command::Export { dir } => export(&dir, data_dir, posts.as_ref(), &users).await?,
+        Command::Import { dir } => import(&dir, data_dir, posts.as_ref(), &mut users).await?,
+    }
+    Ok(())
+}
+
+/// Writes `users.json`, `posts/<username>/<id>.json` with attachments
+/// inlined, and `files/<username>/` with each user's templates
+async fn export(dir: &Path, data_dir: &Path, posts: &dyn PostStore, users: &Users) -> Result<()> {
+    fs::create_dir_all(dir).await.map_err(internal)?;
+    FsUserStore::new(dir).save(&users.users).await?;
+
+    let mut count = 0;
+    for username in posts.usernames().await? {
+        let user_dir = dir.join("posts").join(&username);
+        fs::create_dir_all(&user_dir).await.map_err(internal)?;
+        for summary in posts.list(&username).await? {
+            let post = posts.load(&username, &summary.id).await?;
+            let json = serde_json::to_string_pretty(&post).map_err(internal)?;
+            fs::write(user_dir.join(format!("{}.json", summary.id)), json)
+                .await
+                .map_err(internal)?;
+            count += 1;
+        }
+    }
+
+    for username in users.users.keys() {
+        copy_files(&data_dir.join(username), &dir.join("files").join(username)).await?;
+    }
+
+    println!(
+        "Exported {} users and {} posts to {}",
+        users.users.len(),
+        count,
+        dir.display()
+    );
+    Ok(())
+}
+
+/// Adds the users and posts of an export; existing users are kept, and
+/// existing posts are republished with the exported version
+async fn import(
+    dir: &Path,
+    data_dir: &Path,
+    posts: &dyn PostStore,
+    users: &mut Users,
+) -> Result<()> {
+    let exported = FsUserStore::new(dir)
+        .load()
+        .await?
+        .ok_or_else(|| internal(format!("{} has no users.json", dir.display())))?;
+    for username in users.import_users(exported).await? {
+        println!("Added user {}", username);
+    }
+
+    let mut count = 0;
+    for (username, user_dir) in subdirectories(&dir.join("posts")).await? {
+        check_id(&username)?;
+        let mut entries = fs::read_dir(&user_dir).await.map_err(internal)?;
+        while let Some(entry) = entries.next_entry().await.map_err(internal)? {
+            let path = entry.path();
+            let Some(id) = path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .and_then(|n| n.strip_suffix(".json"))
+            else {
+                continue;
+            };
+
+            let content = fs::read_to_string(&path).await.map_err(internal)?;
+            let mut post: Post = serde_json::from_str(&content)
+                .map_err(|e| internal(format!("{}: {}", path.display(), e)))?;
+            post.metadata.id = Some(id.to_string());
+            posts.save(&username, id, &post).await?;
+            count += 1;
+        }
+    }
+
+    for (username, files_dir) in subdirectories(&dir.join("files")).await? {
+        check_id(&username)?;
+        for filename in copy_files(&files_dir, &data_dir.join(&username)).await? {
+            posts.user_file_written(&username, filename).await?;
+        }
+    }
+
+    println!("Imported {} posts from {}", count, dir.display());
+    Ok(())
+}
+
+/// Lists the directories directly inside `dir` by name, if it exists
+async fn subdirectories(dir: &Path) -> Result<Vec<(String, PathBuf)>> {
+    let mut dirs = Vec::new();
+    if !dir.exists() {
+        return Ok(dirs);
+    }
+
+    let mut entries = fs::read_dir(dir).await.map_err(internal)?;
+    while let Some(entry) = entries.next_entry().await.map_err(internal)? {
+        if entry.file_type().await.map_err(internal)?.is_dir() {
+            dirs.push((
+                entry.file_name().to_string_lossy().to_string(),
+                entry.path(),
+            ));
+        }
+    }
+    Ok(dirs)
+}
+
+/// Copies the templates a user can upload through `/_files/`, returning
+/// those that were found
+async fn copy_files(from: &Path, to: &Path) -> Result<Vec<&'static str>> {
+    let mut copied = Vec::new();
+    for filename in ALLOWED_FILES {
+        let source = from.join(filename);
+        if source.exists() {
+            fs::create_dir_all(to).await.map_err(internal)?;
+            fs::copy(&source, to.join(filename))
+                .await
+                .map_err(internal)?;
+            copied.push(filename);
+        }
+    }
+    Ok(copied)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        models::metadata::Metadata,
+        storage::fs::{FsBlobStore, FsPostStore},
+    };
+    use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+    use std::collections::HashMap;
+
+    /// File storage in a new temporary data directory
+    async fn data_dir() -> (PathBuf, Arc<dyn PostStore>, Arc<RwLock<Users>>) {
+        let dir = std::env::temp_dir().join(format!("dollpublish-cli-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let blobs = Arc::new(FsBlobStore::new(&dir));
+        let posts = Arc::new(FsPostStore::new(dir.clone(), blobs, None));
+        let users = Users::load_or_create(Arc::new(FsUserStore::new(&dir)))
+            .await
+            .unwrap();
+        (dir, posts, users)
+    }
+
+    async fn run_args(
+        args: &[&str],
+        dir: &Path,
+        posts: &Arc<dyn PostStore>,
+        users: &Arc<RwLock<Users>>,
+    ) -> Result<()> {
+        let cli = Cli::try_parse_from([&["dollpublish"], args].concat()).unwrap();
+        run(cli.command.unwrap(), dir, posts.clone(), users.clone()).await
+    }
+
+    fn post(id: &str) -> Post {
+        Post {
+            name: "Moon".to_string(),
+            path: "moon.md".to_string(),
+            metadata: Metadata {
+                id: Some(id.to_string()),
+                extra: HashMap::new(),
+            },
+            content: "![[moon.png]]".to_string(),
+            attachments: Some(HashMap::from([(
+                "moon.png".to_string(),
+                BASE64.encode(b"round"),
+            )])),
+            attachment_hashes: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn imports_what_was_exported() {
+        let (source, posts, users) = data_dir().await;
+        let key = users.write().await.create_user("doll").await.unwrap();
+        posts.save("doll", "moon", &post("moon")).await.unwrap();
+        std::fs::write(source.join("doll").join("template.html"), "{{{content}}}").unwrap();
+
+        let export_dir =
+            std::env::temp_dir().join(format!("dollpublish-export-{}", uuid::Uuid::new_v4()));
+        let export_arg = export_dir.to_str().unwrap();
+        run_args(&["export", export_arg], &source, &posts, &users)
+            .await
+            .unwrap();
+
+        let (target, posts, users) = data_dir().await;
+        run_args(&["import", export_arg], &target, &posts, &users)
+            .await
+            .unwrap();
+        let found = users.read().await.verify_credentials(&key, "").unwrap();
+        assert_eq!(found.username, "doll");
+        let imported = posts.load("doll", "moon").await.unwrap();
+        assert_eq!(imported.content, "![[moon.png]]");
+        assert_eq!(imported.attachments, post("moon").attachments);
+        let template = std::fs::read_to_string(target.join("doll").join("template.html")).unwrap();
+        assert_eq!(template, "{{{content}}}");
+
+        std::fs::remove_dir_all(&source).unwrap();
+        std::fs::remove_dir_all(&export_dir).unwrap();
+        std::fs::remove_dir_all(&target).unwrap();
+    }
+
+    #[tokio::test]
+    async fn deletes_posts_to_the_trash_unless_purged() {
+        let (dir, posts, users) = data_dir().await;
+        users.write().await.create_user("doll").await.unwrap();
+        posts.save("doll", "kept", &post("kept")).await.unwrap();
+        posts.save("doll", "gone", &post("gone")).await.unwrap();
+
+        run_args(&["post", "delete", "doll", "kept"], &dir, &posts, &users)
+            .await
+            .unwrap();
+        run_args(
+            &["post", "delete", "--purge", "doll", "gone"],
+            &dir,
+            &posts,
+            &users,
+        )
+        .await
+        .unwrap();
+
+        assert!(posts.list("doll").await.unwrap().is_empty());
+        let trash: Vec<String> = posts
+            .trash("doll")
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.id)
+            .collect();
+        assert_eq!(trash, ["kept"]);
+        assert!(posts.revisions("doll", "gone").await.unwrap().is_empty());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,152 @@
+#[cfg(feature = "s3")]
+use crate::storage::s3::S3Config;
+use crate::utils::rate_limit::RateLimitConfig;
+use dotenvy::dotenv;
+use std::{env, path::PathBuf, time::Duration};
+
+#[derive(Clone)]
+pub enum StorageBackend {
+    Fs,
+    #[cfg(feature = "sqlite")]
+    Sqlite(PathBuf),
+}
+
+#[derive(Clone)]
+pub enum AttachmentBackend {
+    /// Attachments are kept by the post storage backend
+    Native,
+    #[cfg(feature = "s3")]
+    S3(S3Config),
+}
+
+#[derive(Clone)]
+pub struct Config {
+    pub data_dir: PathBuf,
+    pub bind_addr: String,
+    pub port: u16,
+    pub storage: StorageBackend,
+    pub attachments: AttachmentBackend,
+    /// How often unreferenced blobs are collected; `None` disables collection
+    pub gc_interval: Option<Duration>,
+    /// How many revisions to keep per post; `None` keeps them all
+    pub revision_limit: Option<usize>,
+    /// How long unpublished posts stay in the trash before being purged
+    pub trash_retention: Duration,
+    /// Largest blob `/_moon/blobs/:hash` accepts, in bytes
+    pub max_blob_bytes: u64,
+    /// Commit every change to a git repository in each user directory
+    #[cfg_attr(not(feature = "git"), allow(dead_code))]
+    pub git: bool,
+    /// Credential for the `/_admin/` API; `None` disables it
+    pub admin_key: Option<String>,
+    pub rate_limit: RateLimitConfig,
+    /// How long a browser login lasts
+    pub session_lifetime: Duration,
+    /// Only send session cookies over HTTPS
+    pub secure_cookies: bool,
+}
+
+fn env_number<T: std::str::FromStr>(name: &str, default: T) -> T {
+    env::var(name)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        dotenv().ok();
+
+        let data_dir = env::var("MOON_DATA_DIR").unwrap_or_else(|_| "./data".to_string());
+        let data_dir = PathBuf::from(data_dir);
+        let bind_addr = env::var("MOON_BIND_ADDR").unwrap_or_else(|_| "0.0.0.0".to_string());
+        let port = env::var("MOON_PORT")
+            .ok()
+            .and_then(|p| p.parse().ok())
+            .unwrap_or(3000);
+
+        let storage = match env::var("MOON_STORAGE").as_deref() {
+            Ok("fs") | Err(_) => StorageBackend::Fs,
+            #[cfg(feature = "sqlite")]
+            Ok("sqlite") => StorageBackend::Sqlite(
+                env::var("MOON_SQLITE_PATH")
+                    .map(PathBuf::from)
+                    .unwrap_or_else(|_| data_dir.join("dollpublish.db")),
+            ),
+            Ok(other) => panic!("Unsupported MOON_STORAGE backend: {}", other),
+        };
+
+        let attachments = match env::var("MOON_S3_BUCKET") {
+            Err(_) => AttachmentBackend::Native,
+            #[cfg(feature = "s3")]
+            Ok(bucket) => AttachmentBackend::S3(S3Config {
+                endpoint: env::var("MOON_S3_ENDPOINT")
+                    .unwrap_or_else(|_| "https://s3.amazonaws.com".to_string()),
+                bucket,
+                region: env::var("MOON_S3_REGION").unwrap_or_else(|_| "us-east-1".to_string()),
+                access_key: env::var("MOON_S3_ACCESS_KEY").expect("MOON_S3_ACCESS_KEY is not set"),
+                secret_key: env::var("MOON_S3_SECRET_KEY").expect("MOON_S3_SECRET_KEY is not set"),
+                path_style: env::var("MOON_S3_PATH_STYLE")
+                    .map(|v| v != "false")
+                    .unwrap_or(true),
+                presign_expiry: env::var("MOON_S3_PRESIGN_EXPIRY")
+                    .ok()
+                    .and_then(|v| v.parse().ok()),
+            }),
+            #[cfg(not(feature = "s3"))]
+            Ok(_) => {
+                panic!("MOON_S3_BUCKET is set but dollpublish was built without the s3 feature")
+            }
+        };
+
+        let gc_interval = env_number("MOON_GC_INTERVAL", 3600);
+        let gc_interval = (gc_interval > 0).then(|| Duration::from_secs(gc_interval));
+
+        let revision_limit = env_number("MOON_REVISION_LIMIT", 20);
+        let revision_limit = (revision_limit > 0).then_some(revision_limit);
+
+        let trash_retention: u64 = env_number("MOON_TRASH_RETENTION_DAYS", 30);
+        let trash_retention = Duration::from_secs(trash_retention.saturating_mul(86400));
+
+        let max_blob_bytes = env_number("MOON_MAX_BLOB_BYTES", 100 * 1024 * 1024);
+
+        let git = env::var("MOON_GIT").is_ok_and(|v| v == "true" || v == "1");
+        #[cfg(not(feature = "git"))]
+        if git {
+            panic!("MOON_GIT is set but dollpublish was built without the git feature");
+        }
+        if git && !matches!(storage, StorageBackend::Fs) {
+            panic!("MOON_GIT requires the fs storage backend");
+        }
+
+        let admin_key = env::var("MOON_ADMIN_KEY").ok().filter(|k| !k.is_empty());
+
+        let rate_limit = RateLimitConfig {
+            per_minute: env_number("MOON_RATE_LIMIT", 120),
+            burst: env_number("MOON_RATE_LIMIT_BURST", 30),
+            max_failures: env_number("MOON_LOCKOUT_FAILURES", 10),
+            lockout: Duration::from_secs(env_number("MOON_LOCKOUT_SECONDS", 900)),
+            trust_proxy: env::var("MOON_TRUST_PROXY").is_ok_and(|v| v == "true" || v == "1"),
+        };
+
+        let session_lifetime = Duration::from_secs(env_number("MOON_SESSION_HOURS", 168) * 3600);
+        let secure_cookies = env::var("MOON_SECURE_COOKIES").is_ok_and(|v| v == "true" || v == "1");
+
+        Config {
+            data_dir,
+            bind_addr,
+            port,
+            storage,
+            attachments,
+            gc_interval,
+            revision_limit,
+            trash_retention,
+            max_blob_bytes,
+            git,
+            admin_key,
+            rate_limit,
+            session_lifetime,
+            secure_cookies,
+        }
+    }
+}
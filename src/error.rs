@@ -1,4 +1,9 @@
-use axum::{http::StatusCode, response::IntoResponse, Json};
+use crate::utils::rate_limit::FailedAuthentication;
+use axum::{
+    http::{header, HeaderValue, StatusCode},
+    response::IntoResponse,
+    Json,
+};
 use serde_json::json;
 use thiserror::Error;
 
@@ -6,18 +11,45 @@ use thiserror::Error;
 pub enum AppError {
     #[error("Authentication failed")]
     AuthenticationError,
+    /// Credentials were given but are wrong, which counts towards a lockout
+    #[error("Authentication failed")]
+    InvalidCredentials,
     #[error("Not found")]
     NotFound,
     #[error("Internal server error: {0}")]
     Internal(String),
     #[error("Invalid file")]
     InvalidFile,
+    #[error("Uploaded content does not match its hash")]
+    HashMismatch,
+    #[error("Attachment blob {0} has not been uploaded")]
+    MissingBlob(String),
+    #[error("Attachment {0} is not valid base64")]
+    InvalidAttachment(String),
+    #[error("Conflict: {0}")]
+    Conflict(String),
+    #[error("Bad request: {0}")]
+    BadRequest(String),
+    #[error("Forbidden: {0}")]
+    Forbidden(String),
+    /// Carries the largest size allowed, in bytes
+    #[error("Upload too large")]
+    PayloadTooLarge(u64),
+    /// Carries the number of seconds to wait before retrying
+    #[error("Too many requests")]
+    TooManyRequests(u64),
 }
 
 impl IntoResponse for AppError {
     fn into_response(self) -> axum::response::Response {
+        let retry_after = match &self {
+            AppError::TooManyRequests(seconds) => Some(*seconds),
+            _ => None,
+        };
+        let failed_authentication = matches!(self, AppError::InvalidCredentials);
+
         let (status, message) = match self {
-            AppError::AuthenticationError => (
+            AppError::AuthenticationError | AppError::InvalidCredentials => (
                 StatusCode::UNAUTHORIZED,
                 "Authentication failed".to_string(),
             ),
@@ -27,9 +59,41 @@ impl IntoResponse for AppError {
                 StatusCode::BAD_REQUEST,
                 "Invalid file operation".to_string(),
             ),
+            AppError::HashMismatch => (
+                StatusCode::BAD_REQUEST,
+                "Uploaded content does not match its hash".to_string(),
+            ),
+            AppError::MissingBlob(hash) => (
+                StatusCode::BAD_REQUEST,
+                format!("Attachment blob {} has not been uploaded", hash),
+            ),
+            AppError::InvalidAttachment(filename) => (
+                StatusCode::BAD_REQUEST,
+                format!("Attachment {} is not valid base64", filename),
+            ),
+            AppError::Conflict(msg) => (StatusCode::CONFLICT, msg),
+            AppError::BadRequest(msg) => (StatusCode::BAD_REQUEST, msg),
+            AppError::Forbidden(msg) => (StatusCode::FORBIDDEN, msg),
+            AppError::PayloadTooLarge(limit) => (
+                StatusCode::PAYLOAD_TOO_LARGE,
+                format!("Uploads are limited to {} bytes", limit),
+            ),
+            AppError::TooManyRequests(_) => (
+                StatusCode::TOO_MANY_REQUESTS,
+                "Too many requests".to_string(),
+            ),
         };
 
-        (status, Json(json!({ "error": message }))).into_response()
+        let mut response = (status, Json(json!({ "error": message }))).into_response();
+        if let Some(seconds) = retry_after {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(seconds));
+        }
+        if failed_authentication {
+            response.extensions_mut().insert(FailedAuthentication);
+        }
+        response
     }
 }
 
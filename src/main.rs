@@ -1,69 +1,358 @@
+mod cli;
+mod config;
 mod error;
+mod markdown;
 mod models;
 mod routes;
+mod storage;
 mod utils;
 
-use axum::Router;
-use dotenvy::dotenv;
+use axum::{middleware, Router};
+use clap::Parser;
+use cli::{Cli, Command};
+use config::{AttachmentBackend, Config, StorageBackend};
+use markdown::wikilink::NoteIndexes;
 use models::user::Users;
-use std::{env, path::PathBuf, sync::Arc};
-use tokio::sync::Mutex;
+use std::{net::SocketAddr, path::PathBuf, sync::Arc};
+use storage::{
+    fs::{FsBlobStore, FsPostStore, FsUserStore},
+    gc, BlobStore, PostStore, UserStore,
+};
+use tokio::sync::RwLock;
 
-use utils::template::Templates;
+use utils::{
+    audit::AuditLog,
+    rate_limit::{rate_limit, RateLimiter},
+    session::Sessions,
+    template::Templates,
+    uploads::UploadedBlobs,
+};
+
+/// Every route, with only the authenticated ones rate limited
+fn app(state: AppState, limiter: RateLimiter) -> Router {
+    let authenticated = Router::new()
+        .merge(routes::moon::routes::moon_routes())
+        .merge(routes::files::file_routes())
+        .merge(routes::admin::admin_routes())
+        .merge(routes::session::session_routes())
+        .merge(routes::dashboard::dashboard_routes())
+        .layer(middleware::from_fn_with_state(limiter, rate_limit));
+
+    Router::new()
+        .merge(authenticated)
+        .merge(routes::view::view_routes())
+        .with_state(state)
+}
 
 #[derive(Clone)]
 pub struct AppState {
-    users: Arc<Mutex<Users>>,
+    users: Arc<RwLock<Users>>,
+    posts: Arc<dyn PostStore>,
+    blobs: Arc<dyn BlobStore>,
     data_dir: PathBuf,
     templates: Templates,
+    /// Guards the `/_admin/` API, which is disabled without one
+    admin_key: Option<String>,
+    audit: AuditLog,
+    sessions: Sessions,
+    notes: NoteIndexes,
+    uploads: UploadedBlobs,
+    /// Largest blob that may be uploaded, in bytes
+    max_blob_bytes: u64,
+    /// Whether client addresses come from `X-Forwarded-For`
+    trust_proxy: bool,
 }
 
-fn get_config() -> (PathBuf, String, u16) {
-    dotenv().ok();
+/// Uses the configured external blob store, falling back to `native`
+fn blob_store(config: &Config, native: Arc<dyn BlobStore>) -> Arc<dyn BlobStore> {
+    match &config.attachments {
+        AttachmentBackend::Native => native,
+        #[cfg(feature = "s3")]
+        AttachmentBackend::S3(s3_config) => {
+            Arc::new(storage::s3::S3BlobStore::new(s3_config.clone()))
+        }
+    }
+}
 
-    let data_dir = env::var("MOON_DATA_DIR").unwrap_or_else(|_| "./data".to_string());
-    let bind_addr = env::var("MOON_BIND_ADDR").unwrap_or_else(|_| "0.0.0.0".to_string());
-    let port = env::var("MOON_PORT")
-        .ok()
-        .and_then(|p| p.parse().ok())
-        .unwrap_or(3000);
+/// Wraps file storage so that every change is committed when git is enabled
+async fn fs_post_store(
+    config: &Config,
+    posts: FsPostStore,
+    blobs: Arc<dyn BlobStore>,
+    housekeeping: bool,
+) -> Arc<dyn PostStore> {
+    #[cfg(feature = "git")]
+    if config.git {
+        let posts = storage::git::GitPostStore::new(posts, blobs, config.data_dir.clone());
+        if housekeeping {
+            posts
+                .commit_existing()
+                .await
+                .expect("Failed to commit existing posts to git");
+        }
+        return Arc::new(posts);
+    }
 
-    (PathBuf::from(data_dir), bind_addr, port)
+    #[cfg(not(feature = "git"))]
+    let _ = (config, blobs, housekeeping);
+    Arc::new(posts)
+}
+
+/// Opens the configured storage. With `housekeeping`, which only the server
+/// asks for, it is first recovered, migrated or imported as needed; other
+/// commands may run next to a server and must leave that to it.
+async fn open_storage(
+    config: &Config,
+    housekeeping: bool,
+) -> (Arc<dyn PostStore>, Arc<dyn BlobStore>, Arc<dyn UserStore>) {
+    let (posts, blobs, users): (Arc<dyn PostStore>, Arc<dyn BlobStore>, Arc<dyn UserStore>) =
+        match &config.storage {
+            StorageBackend::Fs => {
+                let blobs = blob_store(config, Arc::new(FsBlobStore::new(&config.data_dir)));
+                let posts = FsPostStore::new(
+                    config.data_dir.clone(),
+                    blobs.clone(),
+                    config.revision_limit,
+                );
+                if housekeeping {
+                    posts
+                        .recover_interrupted_saves()
+                        .await
+                        .expect("Failed to recover interrupted saves");
+                    posts
+                        .migrate_legacy_attachments()
+                        .await
+                        .expect("Failed to migrate attachments into the blob store");
+                }
+                (
+                    fs_post_store(config, posts, blobs.clone(), housekeeping).await,
+                    blobs,
+                    Arc::new(FsUserStore::new(&config.data_dir)),
+                )
+            }
+            #[cfg(feature = "sqlite")]
+            StorageBackend::Sqlite(path) => {
+                let db = storage::sqlite::SqliteStore::open(path)
+                    .expect("Failed to open SQLite database");
+                let blobs = blob_store(config, Arc::new(db.clone()));
+                let posts = storage::sqlite::SqlitePostStore::new(
+                    db.clone(),
+                    blobs.clone(),
+                    config.revision_limit,
+                );
+                if housekeeping {
+                    posts
+                        .import_fs(&config.data_dir)
+                        .await
+                        .expect("Failed to import data directory into SQLite");
+                }
+                (Arc::new(posts), blobs, Arc::new(db))
+            }
+        };
+
+    (posts, blobs, users)
 }
 
 #[tokio::main]
 async fn main() {
+    let cli = Cli::parse();
+
+    // Command line flags override the environment, before anything else
+    // reads it
+    if let Some(data_dir) = &cli.data_dir {
+        std::env::set_var("MOON_DATA_DIR", data_dir);
+    }
+    if let Some(Command::Serve { bind, port }) = &cli.command {
+        if let Some(bind) = bind {
+            std::env::set_var("MOON_BIND_ADDR", bind);
+        }
+        if let Some(port) = port {
+            std::env::set_var("MOON_PORT", port.to_string());
+        }
+    }
+
     // Get configuration
-    let (data_dir, bind_addr, port) = get_config();
+    let config = Config::from_env();
+    let data_dir = config.data_dir.clone();
 
     // Create data directory if it doesn't exist
     if !data_dir.exists() {
         std::fs::create_dir_all(&data_dir).expect("Failed to create data directory");
     }
 
-    let users = Users::load_or_create(&data_dir)
+    let serving = matches!(cli.command, None | Some(Command::Serve { .. }));
+    let (posts, blobs, user_store) = open_storage(&config, serving).await;
+    let users_path = user_store.watch_path();
+    let users = Users::load_or_create(user_store)
         .await
         .expect("Failed to initialize users");
 
+    match cli.command {
+        None | Some(Command::Serve { .. }) => {}
+        Some(command) => {
+            if let Err(e) = cli::run(command, &data_dir, posts, users).await {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+    }
+
+    if let Some(interval) = config.gc_interval {
+        gc::spawn(posts.clone(), blobs.clone(), interval);
+    }
+    gc::spawn_trash_purge(posts.clone(), config.trash_retention);
+    Users::spawn_record_uses(users.clone());
+    if let Some(path) = users_path {
+        Users::watch(users.clone(), path).expect("Failed to watch users for changes");
+    }
+
     let templates = Templates::new();
 
     let state = AppState {
         users,
+        posts,
+        blobs,
         data_dir,
         templates,
+        admin_key: config.admin_key.clone(),
+        audit: AuditLog::new(&config.data_dir),
+        sessions: Sessions::load_or_create(
+            &config.data_dir,
+            config.session_lifetime,
+            config.secure_cookies,
+        )
+        .expect("Failed to load the session secret"),
+        notes: NoteIndexes::default(),
+        uploads: UploadedBlobs::default(),
+        max_blob_bytes: config.max_blob_bytes,
+        trust_proxy: config.rate_limit.trust_proxy,
     };
 
-    let app = Router::new()
-        .merge(routes::moon::routes::moon_routes())
-        .merge(routes::view::view_routes())
-        .merge(routes::files::file_routes())
-        .with_state(state);
+    let limiter = RateLimiter::new(config.rate_limit.clone());
+    limiter.spawn_prune();
+    let app = app(state, limiter);
 
-    let addr = format!("{}:{}", bind_addr, port);
+    let addr = format!("{}:{}", config.bind_addr, config.port);
     let listener = tokio::net::TcpListener::bind(&addr)
         .await
         .unwrap_or_else(|_| panic!("Failed to bind to {}", addr));
 
     println!("dollpublish has started on http://{}", addr);
-    axum::serve(listener, app).await.unwrap();
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await
+    .unwrap();
+}
+
+#[cfg(test)]
+pub(crate) mod testing {
+    use super::*;
+    use axum::{
+        body::{to_bytes, Body},
+        extract::ConnectInfo,
+        http::{header, Request, Response},
+    };
+    use std::time::Duration;
+    use tower::ServiceExt;
+    use utils::rate_limit::RateLimitConfig;
+
+    pub const ADMIN_KEY: &str = "admin-key";
+
+    /// The whole server over a temporary data directory with file storage,
+    /// in which the user `doll` exists
+    pub struct TestServer {
+        pub state: AppState,
+        app: Router,
+        /// `doll`'s `default` key, which has every scope
+        pub key: String,
+        pub dir: PathBuf,
+    }
+
+    impl TestServer {
+        pub async fn new() -> Self {
+            Self::with_state(|_| {}).await
+        }
+
+        /// A server whose state is first changed by `configure`
+        pub async fn with_state(configure: impl FnOnce(&mut AppState)) -> Self {
+            let dir =
+                std::env::temp_dir().join(format!("dollpublish-server-{}", uuid::Uuid::new_v4()));
+            std::fs::create_dir_all(&dir).unwrap();
+            let blobs: Arc<dyn BlobStore> = Arc::new(FsBlobStore::new(&dir));
+            let posts: Arc<dyn PostStore> =
+                Arc::new(FsPostStore::new(dir.clone(), blobs.clone(), None));
+            let users = Users::load_or_create(Arc::new(FsUserStore::new(&dir)))
+                .await
+                .unwrap();
+            let key = users.write().await.create_user("doll").await.unwrap();
+
+            let mut state = AppState {
+                users,
+                posts,
+                blobs,
+                data_dir: dir.clone(),
+                templates: Templates::new(),
+                admin_key: Some(ADMIN_KEY.to_string()),
+                audit: AuditLog::new(&dir),
+                sessions: Sessions::load_or_create(&dir, Duration::from_secs(3600), false).unwrap(),
+                notes: NoteIndexes::default(),
+                uploads: UploadedBlobs::default(),
+                max_blob_bytes: 1024 * 1024,
+                trust_proxy: false,
+            };
+            configure(&mut state);
+            let limiter = RateLimiter::new(RateLimitConfig {
+                per_minute: 0,
+                burst: 1,
+                max_failures: 0,
+                lockout: Duration::from_secs(60),
+                trust_proxy: false,
+            });
+            Self {
+                app: app(state.clone(), limiter),
+                state,
+                key,
+                dir,
+            }
+        }
+
+        pub async fn send(&self, mut request: Request<Body>) -> Response<Body> {
+            request
+                .extensions_mut()
+                .insert(ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 4000))));
+            self.app.clone().oneshot(request).await.unwrap()
+        }
+
+        /// Gives `doll` a key named `name` with only `scopes`
+        pub async fn key_with(&self, name: &str, scopes: &[models::user::Scope]) -> String {
+            let scopes = scopes.iter().cloned().collect();
+            let mut users = self.state.users.write().await;
+            users.create_key("doll", name, scopes).await.unwrap()
+        }
+
+        /// Logs in as `doll` with their key, returning the session cookie
+        pub async fn login(&self) -> String {
+            let request = Request::post("/_login")
+                .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+                .body(Body::from(format!("username=doll&secret={}", self.key)))
+                .unwrap();
+            let response = self.send(request).await;
+            let cookie = response.headers()[header::SET_COOKIE].to_str().unwrap();
+            cookie.split(';').next().unwrap().to_string()
+        }
+    }
+
+    pub async fn json(response: Response<Body>) -> serde_json::Value {
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    impl Drop for TestServer {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
 }
@@ -0,0 +1,108 @@
+use pulldown_cmark::{Event, Tag, TagEnd};
+use std::collections::HashMap;
+
+/// The fragment used for a heading, as `[[Note#Heading]]` links to it
+pub fn slugify(heading: &str) -> String {
+    let mut slug = String::new();
+    for c in heading.trim().chars() {
+        if c.is_alphanumeric() {
+            slug.extend(c.to_lowercase());
+        } else if (c.is_whitespace() || c == '-' || c == '_')
+            && !slug.is_empty()
+            && !slug.ends_with('-')
+        {
+            slug.push('-');
+        }
+    }
+    slug.trim_end_matches('-').to_string()
+}
+
+/// Splits an Obsidian block id such as `^quote-1` off the end of a block's
+/// text, returning the remaining text and the id without its caret
+pub fn split_block_id(text: &str) -> Option<(&str, &str)> {
+    let text = text.trim_end();
+    let caret = text.rfind('^')?;
+    let id = &text[caret + 1..];
+    let before = &text[..caret];
+
+    let valid = !id.is_empty() && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-');
+    if !valid || !(before.is_empty() || before.ends_with(char::is_whitespace)) {
+        return None;
+    }
+    Some((before.trim_end(), id))
+}
+
+/// Gives headings ids from their text and turns trailing `^id` markers on
+/// paragraphs and list items into ids, so links can point at both
+pub fn add_anchors(events: Vec<Event<'_>>) -> Vec<Event<'_>> {
+    let mut out = Vec::with_capacity(events.len());
+    let mut used: HashMap<String, usize> = HashMap::new();
+    // Where the open heading starts in `out`, and its text so far
+    let mut heading: Option<(usize, String)> = None;
+    // Where each open paragraph or list item starts in `out`
+    let mut blocks = Vec::new();
+
+    for event in events {
+        match event {
+            Event::Start(Tag::Heading { .. }) => {
+                heading = Some((out.len(), String::new()));
+                out.push(event);
+            }
+            Event::Text(ref text) | Event::Code(ref text) if heading.is_some() => {
+                if let Some((_, heading_text)) = &mut heading {
+                    heading_text.push_str(text);
+                }
+                out.push(event);
+            }
+            Event::End(TagEnd::Heading(_)) => {
+                if let Some((start, text)) = heading.take() {
+                    let slug = slugify(&text);
+                    if let Event::Start(Tag::Heading { id: id @ None, .. }) = &mut out[start] {
+                        if !slug.is_empty() {
+                            let count = used.entry(slug.clone()).or_insert(0);
+                            *id = Some(match *count {
+                                0 => slug.into(),
+                                n => format!("{}-{}", slug, n).into(),
+                            });
+                            *count += 1;
+                        }
+                    }
+                }
+                out.push(event);
+            }
+            Event::Start(Tag::Paragraph | Tag::Item) => {
+                blocks.push(out.len());
+                out.push(event);
+            }
+            Event::End(end @ (TagEnd::Paragraph | TagEnd::Item)) => {
+                let start = blocks.pop();
+                let block_id = match out.last() {
+                    Some(Event::Text(text)) => split_block_id(text)
+                        .map(|(before, id)| (before.to_string(), id.to_string())),
+                    _ => None,
+                };
+
+                match (start, block_id) {
+                    (Some(start), Some((before, id))) => {
+                        out.pop();
+                        if before.is_empty() {
+                            // The id was on a line of its own
+                            if matches!(out.last(), Some(Event::SoftBreak)) {
+                                out.pop();
+                            }
+                        } else {
+                            out.push(Event::Text(before.into()));
+                        }
+
+                        let tag = if end == TagEnd::Paragraph { "p" } else { "li" };
+                        out[start] = Event::Html(format!("<{} id=\"^{}\">", tag, id).into());
+                        out.push(Event::Html(format!("</{}>\n", tag).into()));
+                    }
+                    _ => out.push(Event::End(end)),
+                }
+            }
+            _ => out.push(event),
+        }
+    }
+    out
+}
@@ -0,0 +1,203 @@
+use pulldown_cmark::{Event, Tag, TagEnd};
+use std::collections::VecDeque;
+
+/// The marker on the first line of a callout, `[!type]` with an optional
+/// `+` or `-` to make it foldable
+struct Callout {
+    kind: String,
+    /// `None` if the callout cannot be folded, otherwise whether it starts open
+    open: Option<bool>,
+    title: String,
+}
+
+impl Callout {
+    fn parse(text: &str) -> Option<Self> {
+        let rest = text.strip_prefix("[!")?;
+        let (kind, rest) = rest.split_once(']')?;
+        let valid = !kind.is_empty()
+            && kind
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !valid {
+            return None;
+        }
+
+        let (open, title) = match rest.chars().next() {
+            Some('+') => (Some(true), &rest[1..]),
+            Some('-') => (Some(false), &rest[1..]),
+            _ => (None, rest),
+        };
+        Some(Self {
+            kind: kind.to_lowercase(),
+            open,
+            title: title.trim_start().to_string(),
+        })
+    }
+
+    /// The type Obsidian styles this one as, for the aliases it accepts
+    fn style(&self) -> &str {
+        match self.kind.as_str() {
+            "summary" | "tldr" => "abstract",
+            "hint" | "important" => "tip",
+            "check" | "done" => "success",
+            "help" | "faq" => "question",
+            "caution" | "attention" => "warning",
+            "fail" | "missing" => "failure",
+            "error" => "danger",
+            "cite" => "quote",
+            kind => kind,
+        }
+    }
+
+    /// The title shown when none is given, which is the type capitalized
+    fn default_title(&self) -> String {
+        let mut chars = self.kind.chars();
+        match chars.next() {
+            Some(first) => first.to_uppercase().chain(chars).collect(),
+            None => String::new(),
+        }
+    }
+
+    fn open_html(&self) -> String {
+        let aside = format!(
+            "<aside class=\"callout callout-{}\" data-callout=\"{}\">",
+            self.style(),
+            self.kind
+        );
+        match self.open {
+            Some(true) => aside + "<details open><summary class=\"callout-title\">",
+            Some(false) => aside + "<details><summary class=\"callout-title\">",
+            None => aside + "<div class=\"callout-title\">",
+        }
+    }
+
+    fn title_end_html(&self) -> &'static str {
+        match self.open {
+            Some(_) => "</summary><div class=\"callout-content\">\n",
+            None => "</div><div class=\"callout-content\">\n",
+        }
+    }
+
+    fn close_html(&self) -> &'static str {
+        match self.open {
+            Some(_) => "</div></details></aside>\n",
+            None => "</div></aside>\n",
+        }
+    }
+}
+
+/// Turns blockquotes that start with `[!type] Title` into callouts. The rest
+/// of the first line is the title and the rest of the blockquote its content.
+pub fn render_callouts(events: Vec<Event<'_>>) -> Vec<Event<'_>> {
+    let mut out = Vec::with_capacity(events.len());
+    let mut queue: VecDeque<Event> = events.into();
+    // What closes each open blockquote, if it is a callout
+    let mut closing: Vec<Option<&'static str>> = Vec::new();
+
+    while let Some(event) = queue.pop_front() {
+        match event {
+            Event::Start(Tag::BlockQuote(kind)) => {
+                let callout = match (queue.front(), queue.get(1)) {
+                    (Some(Event::Start(Tag::Paragraph)), Some(Event::Text(text))) => {
+                        Callout::parse(text)
+                    }
+                    _ => None,
+                };
+                let Some(callout) = callout else {
+                    closing.push(None);
+                    out.push(Event::Start(Tag::BlockQuote(kind)));
+                    continue;
+                };
+                // The paragraph and text holding the marker
+                queue.pop_front();
+                queue.pop_front();
+
+                out.push(Event::Html(callout.open_html().into()));
+                let mut titled = !callout.title.is_empty();
+                if titled {
+                    out.push(Event::Text(callout.title.clone().into()));
+                }
+                let mut has_content = false;
+                while let Some(event) = queue.pop_front() {
+                    match event {
+                        Event::SoftBreak | Event::HardBreak => {
+                            has_content = true;
+                            break;
+                        }
+                        Event::End(TagEnd::Paragraph) => break,
+                        event => {
+                            titled = true;
+                            out.push(event);
+                        }
+                    }
+                }
+                if !titled {
+                    out.push(Event::Text(callout.default_title().into()));
+                }
+
+                out.push(Event::Html(callout.title_end_html().into()));
+                if has_content {
+                    out.push(Event::Start(Tag::Paragraph));
+                }
+                closing.push(Some(callout.close_html()));
+            }
+            Event::End(TagEnd::BlockQuote(kind)) => match closing.pop().flatten() {
+                Some(html) => out.push(Event::Html(html.into())),
+                None => out.push(Event::End(TagEnd::BlockQuote(kind))),
+            },
+            _ => out.push(event),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::markdown::testing::render_alone;
+
+    #[test]
+    fn renders_plain_and_folded_callouts() {
+        assert_eq!(
+            render_alone("> [!note]\n> body"),
+            "<aside class=\"callout callout-note\" data-callout=\"note\"><div class=\"callout-title\">Note</div><div class=\"callout-content\">\n<p>body</p>\n</div></aside>\n"
+        );
+        assert_eq!(
+            render_alone("> [!TIP]- Folded *title*\n> hidden"),
+            "<aside class=\"callout callout-tip\" data-callout=\"tip\"><details><summary class=\"callout-title\">Folded <em>title</em></summary><div class=\"callout-content\">\n<p>hidden</p>\n</div></details></aside>\n"
+        );
+        assert_eq!(
+            render_alone("> [!faq]+\n> open"),
+            "<aside class=\"callout callout-question\" data-callout=\"faq\"><details open><summary class=\"callout-title\">Faq</summary><div class=\"callout-content\">\n<p>open</p>\n</div></details></aside>\n"
+        );
+    }
+
+    #[test]
+    fn renders_nested_callouts() {
+        assert_eq!(
+            render_alone("> [!warning] Outer\n> text\n>\n> > [!info]- Inner\n> > nested\n>\n> after"),
+            "<aside class=\"callout callout-warning\" data-callout=\"warning\"><div class=\"callout-title\">Outer</div><div class=\"callout-content\">\n\
+             <p>text</p>\n\
+             <aside class=\"callout callout-info\" data-callout=\"info\"><details><summary class=\"callout-title\">Inner</summary><div class=\"callout-content\">\n\
+             <p>nested</p>\n\
+             </div></details></aside>\n\
+             <p>after</p>\n\
+             </div></aside>\n"
+        );
+        assert_eq!(
+            render_alone("> quote\n>\n> > [!note]\n> > inner"),
+            "<blockquote>\n<p>quote</p>\n\
+             <aside class=\"callout callout-note\" data-callout=\"note\"><div class=\"callout-title\">Note</div><div class=\"callout-content\">\n\
+             <p>inner</p>\n\
+             </div></aside>\n\
+             </blockquote>\n"
+        );
+    }
+
+    #[test]
+    fn leaves_other_blockquotes_alone() {
+        assert_eq!(
+            render_alone("> [!bad type] x\n> quote"),
+            "<blockquote>\n<p>[!bad type] x\nquote</p>\n</blockquote>\n"
+        );
+    }
+}
@@ -0,0 +1,189 @@
+use super::{transclude::is_note, wikilink::Wikilink, Note};
+use handlebars::html_escape;
+use pulldown_cmark::{Event, Tag, TagEnd};
+
+/// An Obsidian size hint: `300` for a width or `300x200` for both
+fn parse_size(hint: &str) -> Option<(u32, Option<u32>)> {
+    match hint.trim().split_once('x') {
+        Some((width, height)) => Some((width.parse().ok()?, Some(height.parse().ok()?))),
+        None => Some((hint.trim().parse().ok()?, None)),
+    }
+}
+
+/// Replaces `![[file]]` embeds of the note's attachments with images,
+/// players or download links. Notes are embedded by
+/// [`super::transclude::transclude`] before this runs.
+pub fn embed_attachments<'a>(events: Vec<Event<'a>>, note: &Note) -> Vec<Event<'a>> {
+    let mut out = Vec::with_capacity(events.len());
+    let mut in_code_block = false;
+
+    for event in events {
+        match event {
+            Event::Start(Tag::CodeBlock(_)) => {
+                in_code_block = true;
+                out.push(event);
+            }
+            Event::End(TagEnd::CodeBlock) => {
+                in_code_block = false;
+                out.push(event);
+            }
+            Event::Text(text) if !in_code_block && text.contains("![[") => {
+                split_embeds(&text, note, &mut out)
+            }
+            _ => out.push(event),
+        }
+    }
+    out
+}
+
+fn split_embeds<'a>(text: &str, note: &Note, out: &mut Vec<Event<'a>>) {
+    let mut last = 0;
+    let mut pos = 0;
+    while let Some(found) = text[pos..].find("![[") {
+        let start = pos + found;
+        let Some(length) = text[start + 3..].find("]]") else {
+            break;
+        };
+        let end = start + 3 + length + 2;
+        pos = start + 3;
+
+        let Some(link) = Wikilink::parse(&text[start + 3..end - 2]) else {
+            continue;
+        };
+        if link.target.is_empty() || is_note(link.target) {
+            continue;
+        }
+
+        if start > last {
+            out.push(Event::Text(text[last..start].to_string().into()));
+        }
+        let html = match note.attachment(link.target) {
+            Some(name) => render_embed(name, link.alias, note),
+            None => format!(
+                "<span class=\"wikilink wikilink-unresolved\">{}</span>",
+                html_escape(link.target)
+            ),
+        };
+        out.push(Event::InlineHtml(html.into()));
+        last = end;
+        pos = end;
+    }
+    if last < text.len() {
+        out.push(Event::Text(text[last..].to_string().into()));
+    }
+}
+
+/// `options` is what follows the first `|`: alt text, a size hint, or
+/// `alt|size`
+fn render_embed(name: &str, options: Option<&str>, note: &Note) -> String {
+    let (alt, size) = match options {
+        Some(options) => match options.rsplit_once('|') {
+            Some((alt, hint)) if parse_size(hint).is_some() => (Some(alt.trim()), parse_size(hint)),
+            _ if parse_size(options).is_some() => (None, parse_size(options)),
+            _ => (Some(options), None),
+        },
+        None => (None, None),
+    };
+    let dimensions = match size {
+        Some((width, Some(height))) => format!(" width=\"{}\" height=\"{}\"", width, height),
+        Some((width, None)) => format!(" width=\"{}\"", width),
+        None => String::new(),
+    };
+
+    let src = html_escape(&note.attachment_url(name));
+    let label = html_escape(name);
+    let mime = mime_guess::from_path(name).first_or_octet_stream();
+    match mime.type_() {
+        mime::IMAGE => format!(
+            "<img src=\"{}\" alt=\"{}\"{} />",
+            src,
+            html_escape(alt.unwrap_or(name)),
+            dimensions
+        ),
+        mime::AUDIO => format!(
+            "<audio controls><source src=\"{}\" type=\"{}\">{}</audio>",
+            src,
+            html_escape(mime.as_ref()),
+            label
+        ),
+        mime::VIDEO => format!(
+            "<video controls{}><source src=\"{}\" type=\"{}\">{}</video>",
+            dimensions,
+            src,
+            html_escape(mime.as_ref()),
+            label
+        ),
+        _ => format!(
+            "<a href=\"{}\" download>{}</a>",
+            src,
+            alt.map(html_escape).unwrap_or(label)
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn note(attachments: &[String]) -> Note<'_> {
+        Note {
+            attachments,
+            attachment_base: "attachments/".to_string(),
+        }
+    }
+
+    #[test]
+    fn parses_size_hints() {
+        assert_eq!(parse_size("300"), Some((300, None)));
+        assert_eq!(parse_size(" 300x200 "), Some((300, Some(200))));
+        assert_eq!(parse_size("wide"), None);
+        assert_eq!(parse_size("300xtall"), None);
+    }
+
+    #[test]
+    fn applies_size_hints_and_alt_text() {
+        let attachments = vec!["moon.png".to_string(), "clip.mp4".to_string()];
+        let note = note(&attachments);
+        assert_eq!(
+            render_embed("moon.png", Some("300"), &note),
+            "<img src=\"attachments/moon.png\" alt=\"moon.png\" width=\"300\" />"
+        );
+        assert_eq!(
+            render_embed("moon.png", Some("The moon|300x200"), &note),
+            "<img src=\"attachments/moon.png\" alt=\"The moon\" width=\"300\" height=\"200\" />"
+        );
+        assert_eq!(
+            render_embed("clip.mp4", Some("640x360"), &note),
+            "<video controls width=\"640\" height=\"360\"><source src=\"attachments/clip.mp4\" type=\"video/mp4\">clip.mp4</video>"
+        );
+    }
+
+    #[test]
+    fn escapes_names_and_alt_text() {
+        let attachments = vec!["my \"moon\" #1.png".to_string(), "a<b>.zip".to_string()];
+        let note = note(&attachments);
+        assert_eq!(
+            render_embed(&attachments[0], Some("\"><script>"), &note),
+            "<img src=\"attachments/my%20%22moon%22%20%231.png\" alt=\"&quot;&gt;&lt;script&gt;\" />"
+        );
+        assert_eq!(
+            render_embed(&attachments[1], None, &note),
+            "<a href=\"attachments/a%3Cb%3E.zip\" download>a&lt;b&gt;.zip</a>"
+        );
+    }
+
+    #[test]
+    fn marks_missing_attachments() {
+        let events: Vec<Event> = vec![Event::Text("see ![[gone.png]]".into())];
+        let out = embed_attachments(events, &note(&[]));
+        assert_eq!(
+            out,
+            vec![
+                Event::Text("see ".into()),
+                Event::InlineHtml(
+                    "<span class=\"wikilink wikilink-unresolved\">gone.png</span>".into()
+                ),
+            ]
+        );
+    }
+}
@@ -0,0 +1,262 @@
+use handlebars::html_escape;
+use pulldown_cmark::Event;
+use pulldown_latex::{
+    config::DisplayMode,
+    event::{Content, Event as LatexEvent},
+    push_mathml, Parser as LatexParser, RenderConfig, Storage,
+};
+use std::fmt;
+
+/// Stand-ins for `<`, `>` and `&` in the math, which the MathML writer
+/// outputs unescaped, swapped for entities once it is done. Stand-ins that
+/// were already in the math are written as the character reference given
+/// last, so that they are not swapped too.
+const MASKS: [(char, char, &str, &str); 3] = [
+    ('<', '\u{E000}', "&lt;", "&#xE000;"),
+    ('>', '\u{E001}', "&gt;", "&#xE001;"),
+    ('&', '\u{E002}', "&amp;", "&#xE002;"),
+];
+
+/// Renders `$inline$` and `$$display$$` math to MathML, keeping the TeX as an
+/// annotation so it can still be copied
+pub fn render_math(events: Vec<Event<'_>>) -> Vec<Event<'_>> {
+    events
+        .into_iter()
+        .map(|event| match event {
+            Event::InlineMath(tex) => Event::InlineHtml(mathml(&tex, DisplayMode::Inline).into()),
+            Event::DisplayMath(tex) => Event::InlineHtml(mathml(&tex, DisplayMode::Block).into()),
+            event => event,
+        })
+        .collect()
+}
+
+/// A TeX error, shown in the rendered math with its message escaped
+#[derive(Debug)]
+struct MathError(String);
+
+impl fmt::Display for MathError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&escape_stand_ins(&html_escape(&self.0), |c| c))
+    }
+}
+
+impl std::error::Error for MathError {}
+
+fn mathml(tex: &str, display_mode: DisplayMode) -> String {
+    let storage = Storage::new();
+    let events: Vec<_> = LatexParser::new(tex, &storage).collect();
+    let masked_strings: Vec<Option<String>> = events
+        .iter()
+        .map(|event| match event {
+            Ok(LatexEvent::Content(Content::Number(text) | Content::Function(text))) => {
+                Some(escape_stand_ins(text, mask))
+            }
+            _ => None,
+        })
+        .collect();
+
+    let events = events
+        .into_iter()
+        .zip(&masked_strings)
+        .flat_map(|(event, masked)| match event {
+            Ok(LatexEvent::Content(content)) => mask_content(content, masked.as_deref())
+                .into_iter()
+                .map(|content| Ok(LatexEvent::Content(content)))
+                .collect(),
+            Ok(event) => vec![Ok(event)],
+            Err(e) => vec![Err(MathError(e.to_string()))],
+        });
+
+    let annotation = escape_stand_ins(&html_escape(tex), |c| c);
+    let config = RenderConfig {
+        display_mode,
+        annotation: Some(&annotation),
+        ..RenderConfig::default()
+    };
+    let mut rendered = String::new();
+    if push_mathml(&mut rendered, events, config).is_err() {
+        // Only writing can fail, and strings do not; show the source regardless
+        return format!("<code class=\"math-error\">{}</code>", annotation);
+    }
+
+    for (_, stand_in, entity, _) in MASKS {
+        rendered = rendered.replace(stand_in, entity);
+    }
+    rendered
+}
+
+fn mask(c: char) -> char {
+    MASKS
+        .iter()
+        .find(|(original, _, _, _)| *original == c)
+        .map_or(c, |(_, stand_in, _, _)| *stand_in)
+}
+
+/// The character reference for `c` if it is one of the stand-ins
+fn stand_in_reference(c: char) -> Option<&'static str> {
+    MASKS
+        .iter()
+        .find(|(_, stand_in, _, _)| *stand_in == c)
+        .map(|(_, _, _, reference)| *reference)
+}
+
+/// Writes the stand-ins already in `text` as references, and every other
+/// character as `map` gives it
+fn escape_stand_ins(text: &str, map: fn(char) -> char) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match stand_in_reference(c) {
+            Some(reference) => escaped.push_str(reference),
+            None => escaped.push(map(c)),
+        }
+    }
+    escaped
+}
+
+/// `masked` is the text of a number or function with its characters masked
+fn mask_content<'a>(content: Content<'a>, masked: Option<&'a str>) -> Vec<Content<'a>> {
+    let single = match &content {
+        Content::Ordinary { content, .. }
+        | Content::LargeOp { content, .. }
+        | Content::BinaryOp { content, .. }
+        | Content::Delimiter { content, .. }
+        | Content::Punctuation(content) => Some(*content),
+        _ => None,
+    };
+    if let Some(reference) = single.and_then(stand_in_reference) {
+        return vec![Content::Number(reference)];
+    }
+    // The writer escapes text itself, so stand-ins in it are split out
+    if let Content::Text(text) = content {
+        let mut pieces = Vec::new();
+        let mut start = 0;
+        for (i, c) in text.char_indices() {
+            if let Some(reference) = stand_in_reference(c) {
+                if i > start {
+                    pieces.push(Content::Text(&text[start..i]));
+                }
+                pieces.push(Content::Number(reference));
+                start = i + c.len_utf8();
+            }
+        }
+        if start < text.len() || pieces.is_empty() {
+            pieces.push(Content::Text(&text[start..]));
+        }
+        return pieces;
+    }
+
+    let content = match (content, masked) {
+        (Content::Number(_), Some(masked)) => Content::Number(masked),
+        (Content::Function(_), Some(masked)) => Content::Function(masked),
+        (Content::Ordinary { content, stretchy }, _) => Content::Ordinary {
+            content: mask(content),
+            stretchy,
+        },
+        (Content::LargeOp { content, small }, _) => Content::LargeOp {
+            content: mask(content),
+            small,
+        },
+        (Content::BinaryOp { content, small }, _) => Content::BinaryOp {
+            content: mask(content),
+            small,
+        },
+        (Content::Delimiter { content, size, ty }, _) => Content::Delimiter {
+            content: mask(content),
+            size,
+            ty,
+        },
+        (Content::Punctuation(content), _) => Content::Punctuation(mask(content)),
+        (Content::Relation { content, small }, _) => {
+            let mut buf = [0; 8];
+            let text = String::from_utf8_lossy(content.encode_utf8_to_buf(&mut buf)).into_owned();
+            if !text.contains(['<', '>', '&']) {
+                return vec![Content::Relation { content, small }];
+            }
+            // Relations cannot be rebuilt with other characters, but as
+            // operators these still get a relation's spacing
+            return text
+                .chars()
+                .map(|c| Content::Ordinary {
+                    content: mask(c),
+                    stretchy: true,
+                })
+                .collect();
+        }
+        (content, _) => content,
+    };
+    vec![content]
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::markdown::testing::render_alone;
+
+    #[test]
+    fn leaves_dollar_amounts_as_text() {
+        assert_eq!(
+            render_alone("It costs $5 and $10 today"),
+            "<p>It costs $5 and $10 today</p>\n"
+        );
+    }
+
+    #[test]
+    fn renders_math_to_mathml() {
+        assert_eq!(
+            render_alone("$\\frac{1}{2}$"),
+            "<p><math display=\"inline\"><semantics><mrow><mfrac><mrow><mn>1</mn></mrow><mrow><mn>2</mn></mrow></mfrac></mrow>\
+             <annotation encoding=\"application/x-tex\">\\frac{1}{2}</annotation></semantics></math></p>\n"
+        );
+    }
+
+    #[test]
+    fn escapes_markup_in_math() {
+        let html = render_alone("$\\text{<script>}$");
+        assert!(!html.contains("<script"), "{}", html);
+        assert!(html.contains("<mtext>&lt;script&gt;</mtext>"), "{}", html);
+        assert!(
+            html.contains("\\text{&lt;script&gt;}</annotation>"),
+            "{}",
+            html
+        );
+
+        let html = render_alone("$$a < b \\& c$$ and $\\operatorname{a<b}$");
+        assert!(html.contains("<mo stretchy=\"true\">&lt;</mo>"), "{}", html);
+        assert!(html.contains("<mi>&amp;</mi>"), "{}", html);
+        assert!(html.contains("<mi>a&lt;b</mi>"), "{}", html);
+    }
+
+    #[test]
+    fn keeps_stand_in_characters_apart_from_markup() {
+        let html =
+            render_alone("$\u{E000}b\u{E001} \\text{\u{E000}i\u{E001}} \\operatorname{\u{E002}}$");
+        assert!(
+            !html.contains("&lt;") && !html.contains("&amp;"),
+            "{}",
+            html
+        );
+        assert!(
+            !html.contains(['\u{E000}', '\u{E001}', '\u{E002}']),
+            "{}",
+            html
+        );
+        assert!(
+            html.contains("<mn>&#xE000;</mn><mi>b</mi><mn>&#xE001;</mn>"),
+            "{}",
+            html
+        );
+        assert!(
+            html.contains("<mn>&#xE000;</mn><mtext>i</mtext><mn>&#xE001;</mn>"),
+            "{}",
+            html
+        );
+        assert!(html.contains("<mi>&#xE002;</mi>"), "{}", html);
+        assert!(html.contains("\\text{&#xE000;i&#xE001;}"), "{}", html);
+    }
+
+    #[test]
+    fn escapes_errors() {
+        let html = render_alone("$\\badcommand{<x>}$");
+        assert!(html.contains("<merror"), "{}", html);
+        assert!(!html.contains("<x>"), "{}", html);
+    }
+}
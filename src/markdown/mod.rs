@@ -0,0 +1,211 @@
+pub mod anchors;
+pub mod callout;
+pub mod embed;
+pub mod math;
+pub mod transclude;
+pub mod wikilink;
+
+use crate::{
+    error::{AppError, Result},
+    models::post::Post,
+    storage::PostStore,
+};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use pulldown_cmark::{html, Event, Options, Parser, TextMergeStream};
+use std::{collections::HashMap, sync::Arc};
+use wikilink::{NoteIndex, NoteIndexes};
+
+/// Characters escaped in a single URL path segment
+const SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// Most other notes loaded to render one post's embeds
+const MAX_EMBEDDED_NOTES: usize = 64;
+
+/// What a post is rendered against: its author's other posts and its own
+/// attachments
+pub struct RenderContext {
+    pub username: String,
+    /// Id of the post being rendered, if it has been published
+    pub id: Option<String>,
+    pub attachments: Vec<String>,
+    pub notes: Arc<NoteIndex>,
+    /// The notes the post embeds, directly or through other embeds, by id
+    pub sources: HashMap<String, EmbeddedNote>,
+}
+
+/// Another post, as loaded to embed it
+pub struct EmbeddedNote {
+    pub content: String,
+    pub attachments: Vec<String>,
+}
+
+/// The note being rendered, either the post itself or a note embedded in it
+pub struct Note<'c> {
+    pub attachments: &'c [String],
+    /// What the note's attachment URLs start with
+    pub attachment_base: String,
+}
+
+impl<'c> Note<'c> {
+    /// The attachment `target` names. Obsidian may write the attachment's
+    /// folder in the vault, which is not kept when it is published.
+    pub fn attachment(&self, target: &str) -> Option<&'c str> {
+        let file_name = target.rsplit(['/', '\\']).next().unwrap_or(target);
+        self.attachments
+            .iter()
+            .find(|name| *name == target)
+            .or_else(|| self.attachments.iter().find(|name| *name == file_name))
+            .map(String::as_str)
+    }
+
+    pub fn attachment_url(&self, name: &str) -> String {
+        format!("{}{}", self.attachment_base, encode_segment(name))
+    }
+}
+
+impl RenderContext {
+    pub fn new(username: &str, attachments: Vec<String>, notes: Arc<NoteIndex>) -> Self {
+        Self {
+            username: username.to_string(),
+            id: None,
+            attachments,
+            notes,
+            sources: HashMap::new(),
+        }
+    }
+
+    /// A context for rendering `post`, with every post `username` has
+    /// published and the notes `post` embeds
+    pub async fn load(
+        posts: &dyn PostStore,
+        notes: &NoteIndexes,
+        username: &str,
+        post: &Post,
+        attachments: Vec<String>,
+    ) -> Result<Self> {
+        let notes = notes.get(posts, username).await?;
+        let mut context = Self::new(username, attachments, notes);
+        context.id = post.metadata.id.clone();
+
+        let mut pending = context.embedded_ids(&post.content);
+        for _ in 0..transclude::MAX_DEPTH {
+            let mut next = Vec::new();
+            for id in pending {
+                if context.sources.len() >= MAX_EMBEDDED_NOTES {
+                    return Ok(context);
+                }
+                if context.sources.contains_key(&id) {
+                    continue;
+                }
+                let note = match posts.load_document(username, &id).await {
+                    Ok(note) => note,
+                    Err(AppError::NotFound) => continue,
+                    Err(e) => return Err(e),
+                };
+                let attachments = posts.attachment_names(username, &id).await?;
+                next.extend(context.embedded_ids(&note.content));
+                context.sources.insert(
+                    id,
+                    EmbeddedNote {
+                        content: note.content,
+                        attachments,
+                    },
+                );
+            }
+            pending = next;
+        }
+        Ok(context)
+    }
+
+    /// Ids of the published notes `markdown` embeds
+    fn embedded_ids(&self, markdown: &str) -> Vec<String> {
+        transclude::embedded_notes(markdown)
+            .iter()
+            .filter_map(|link| self.notes.resolve(link.target))
+            .map(str::to_string)
+            .collect()
+    }
+}
+
+fn options() -> Options {
+    let mut options = Options::empty();
+    options.insert(Options::ENABLE_STRIKETHROUGH);
+    options.insert(Options::ENABLE_TABLES);
+    options.insert(Options::ENABLE_FOOTNOTES);
+    options.insert(Options::ENABLE_TASKLISTS);
+    options.insert(Options::ENABLE_MATH);
+    options
+}
+
+/// Renders a post's markdown, including the Obsidian syntax we support, to HTML
+pub fn render(markdown: &str, context: &RenderContext) -> String {
+    let mut stack: Vec<String> = context.id.iter().cloned().collect();
+    let note = Note {
+        attachments: &context.attachments,
+        // Relative, so revisions link to their own copies
+        attachment_base: "attachments/".to_string(),
+    };
+    render_note(markdown, &note, context, &mut stack)
+}
+
+/// Renders one note's markdown; `stack` holds the ids of the notes it is
+/// embedded in
+fn render_note(
+    markdown: &str,
+    note: &Note,
+    context: &RenderContext,
+    stack: &mut Vec<String>,
+) -> String {
+    let events: Vec<Event> = TextMergeStream::new(Parser::new_ext(markdown, options())).collect();
+    let events = callout::render_callouts(events);
+    let events = anchors::add_anchors(events);
+    let events = transclude::transclude(events, note, context, stack);
+    let events = embed::embed_attachments(events, note);
+    let events = wikilink::link_wikilinks(events, note, context);
+    let events = math::render_math(events);
+
+    let mut rendered = String::new();
+    html::push_html(&mut rendered, events.into_iter());
+    rendered
+}
+
+/// Percent-encodes `segment` for use as one part of a URL path
+pub fn encode_segment(segment: &str) -> String {
+    utf8_percent_encode(segment, SEGMENT).to_string()
+}
+
+#[cfg(test)]
+pub(crate) mod testing {
+    use super::{render, NoteIndex, RenderContext};
+    use crate::storage::PostSummary;
+    use std::sync::Arc;
+
+    /// A context for rendering one of `doll`'s posts, with `posts` published
+    /// as `(id, vault path)`
+    pub fn context(posts: &[(&str, &str)]) -> RenderContext {
+        let posts: Vec<PostSummary> = posts
+            .iter()
+            .map(|(id, path)| PostSummary {
+                id: id.to_string(),
+                name: path
+                    .rsplit('/')
+                    .next()
+                    .unwrap()
+                    .trim_end_matches(".md")
+                    .to_string(),
+                path: path.to_string(),
+                modified: 0,
+            })
+            .collect();
+        RenderContext::new("doll", Vec::new(), Arc::new(NoteIndex::new(&posts)))
+    }
+
+    /// Renders `markdown` with no other posts or attachments
+    pub fn render_alone(markdown: &str) -> String {
+        render(markdown, &context(&[]))
+    }
+}
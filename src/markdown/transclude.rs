@@ -0,0 +1,337 @@
+use super::{
+    anchors::{slugify, split_block_id},
+    encode_segment, options, render_note,
+    wikilink::{render_link, Wikilink},
+    Note, RenderContext,
+};
+use pulldown_cmark::{Event, HeadingLevel, Parser, Tag, TagEnd};
+
+/// How many notes deep `![[Note]]` embeds are followed
+pub const MAX_DEPTH: usize = 5;
+
+/// Whether an embed target names a note rather than an attachment such as
+/// `image.png`. Notes may be named with or without `.md`.
+pub fn is_note(target: &str) -> bool {
+    target.to_lowercase().ends_with(".md") || mime_guess::from_path(target).first().is_none()
+}
+
+/// Every `![[Note]]` embed in `markdown`, whether or not it can be resolved
+pub fn embedded_notes(markdown: &str) -> Vec<Wikilink<'_>> {
+    let mut links = Vec::new();
+    let mut pos = 0;
+    while let Some(found) = markdown[pos..].find("![[") {
+        let start = pos + found + 3;
+        let Some(length) = markdown[start..].find("]]") else {
+            break;
+        };
+        if let Some(link) = Wikilink::parse(&markdown[start..start + length]) {
+            if !link.target.is_empty() && is_note(link.target) {
+                links.push(link);
+            }
+        }
+        pos = start;
+    }
+    links
+}
+
+/// Replaces `![[Note]]`, `![[Note#Heading]]` and `![[Note#^block]]` with the
+/// rendered note or section. `stack` holds the ids of the notes being
+/// rendered, outermost first, so an embed of any of them is only linked.
+pub fn transclude<'a>(
+    events: Vec<Event<'a>>,
+    note: &Note,
+    context: &RenderContext,
+    stack: &mut Vec<String>,
+) -> Vec<Event<'a>> {
+    let mut out = Vec::with_capacity(events.len());
+    let mut in_code_block = false;
+    let mut in_paragraph = false;
+
+    for event in events {
+        match event {
+            Event::Start(Tag::CodeBlock(_)) => {
+                in_code_block = true;
+                out.push(event);
+            }
+            Event::End(TagEnd::CodeBlock) => {
+                in_code_block = false;
+                out.push(event);
+            }
+            Event::Start(Tag::Paragraph) => {
+                in_paragraph = true;
+                out.push(event);
+            }
+            Event::End(TagEnd::Paragraph) => {
+                in_paragraph = false;
+                close_paragraph(&mut out);
+            }
+            Event::Text(text) if !in_code_block && text.contains("![[") => {
+                split_embeds(&text, note, context, stack, in_paragraph, &mut out)
+            }
+            // A line break left at the start of a paragraph reopened after an embed
+            Event::SoftBreak if matches!(out.last(), Some(Event::Start(Tag::Paragraph))) => {}
+            _ => out.push(event),
+        }
+    }
+    out
+}
+
+/// Ends the current paragraph, dropping it if nothing was left in it
+fn close_paragraph(out: &mut Vec<Event<'_>>) {
+    while matches!(out.last(), Some(Event::SoftBreak))
+        || matches!(out.last(), Some(Event::Text(text)) if text.trim().is_empty())
+    {
+        out.pop();
+    }
+    if matches!(out.last(), Some(Event::Start(Tag::Paragraph))) {
+        out.pop();
+    } else {
+        out.push(Event::End(TagEnd::Paragraph));
+    }
+}
+
+fn split_embeds<'a>(
+    text: &str,
+    note: &Note,
+    context: &RenderContext,
+    stack: &mut Vec<String>,
+    in_paragraph: bool,
+    out: &mut Vec<Event<'a>>,
+) {
+    let mut last = 0;
+    let mut pos = 0;
+    while let Some(found) = text[pos..].find("![[") {
+        let start = pos + found;
+        let Some(length) = text[start + 3..].find("]]") else {
+            break;
+        };
+        let end = start + 3 + length + 2;
+        pos = start + 3;
+
+        let Some(link) = Wikilink::parse(&text[start + 3..end - 2]) else {
+            continue;
+        };
+        if link.target.is_empty() || !is_note(link.target) {
+            continue;
+        }
+
+        push_text(out, &text[last..start]);
+        match embed_note(&link, note, context, stack) {
+            // Notes are blocks, so they go between paragraphs rather than in one
+            Some(html) if in_paragraph => {
+                close_paragraph(out);
+                out.push(Event::Html(html.into()));
+                out.push(Event::Start(Tag::Paragraph));
+            }
+            Some(html) => out.push(Event::Html(html.into())),
+            None => out.push(Event::InlineHtml(render_link(&link, note, context).into())),
+        }
+        last = end;
+        pos = end;
+    }
+    push_text(out, &text[last..]);
+}
+
+fn push_text(out: &mut Vec<Event<'_>>, text: &str) {
+    let text = match out.last() {
+        Some(Event::Start(Tag::Paragraph)) => text.trim_start(),
+        _ => text,
+    };
+    if !text.is_empty() {
+        out.push(Event::Text(text.to_string().into()));
+    }
+}
+
+/// The rendered note or section, or `None` when it cannot be found or is
+/// already being rendered, in which case the embed is shown as a link
+fn embed_note(
+    link: &Wikilink,
+    note: &Note,
+    context: &RenderContext,
+    stack: &mut Vec<String>,
+) -> Option<String> {
+    let id = context.notes.resolve(link.target)?;
+    let embedded = context.sources.get(id)?;
+    if stack.iter().any(|open| open == id) || stack.len() >= MAX_DEPTH {
+        return None;
+    }
+    let section = match link.anchor {
+        Some(anchor) => section(&embedded.content, anchor)?,
+        None => embedded.content.as_str(),
+    };
+
+    let inner = Note {
+        attachments: &embedded.attachments,
+        attachment_base: format!(
+            "/{}/{}/attachments/",
+            encode_segment(&context.username),
+            encode_segment(id)
+        ),
+    };
+    stack.push(id.to_string());
+    let body = render_note(section, &inner, context, stack);
+    stack.pop();
+
+    Some(format!(
+        "<div class=\"transclusion\">\n{}<div class=\"transclusion-source\">{}</div>\n</div>\n",
+        body,
+        render_link(link, note, context)
+    ))
+}
+
+/// The part of a note's markdown an anchor refers to
+fn section<'s>(markdown: &'s str, anchor: &str) -> Option<&'s str> {
+    match anchor.strip_prefix('^') {
+        Some(block) => block_section(markdown, block),
+        None => heading_section(markdown, &slugify(anchor.rsplit('#').next()?)),
+    }
+}
+
+/// A heading and everything up to the next heading of the same or a higher
+/// level
+fn heading_section<'s>(markdown: &'s str, slug: &str) -> Option<&'s str> {
+    let mut found: Option<(HeadingLevel, usize)> = None;
+    let mut current: Option<(HeadingLevel, usize, String)> = None;
+
+    for (event, range) in Parser::new_ext(markdown, options()).into_offset_iter() {
+        match event {
+            Event::Start(Tag::Heading { level, .. }) => {
+                if let Some((found_level, start)) = found {
+                    if level <= found_level {
+                        return Some(&markdown[start..range.start]);
+                    }
+                }
+                current = Some((level, range.start, String::new()));
+            }
+            Event::Text(text) | Event::Code(text) => {
+                if let Some((_, _, heading)) = &mut current {
+                    heading.push_str(&text);
+                }
+            }
+            Event::End(TagEnd::Heading(_)) => {
+                if let Some((level, start, heading)) = current.take() {
+                    if found.is_none() && slugify(&heading) == slug {
+                        found = Some((level, start));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    found.map(|(_, start)| &markdown[start..])
+}
+
+/// The paragraph or list item marked with `^id`
+fn block_section<'s>(markdown: &'s str, id: &str) -> Option<&'s str> {
+    let mut starts = Vec::new();
+    for (event, range) in Parser::new_ext(markdown, options()).into_offset_iter() {
+        match event {
+            Event::Start(Tag::Paragraph | Tag::Item) => starts.push(range.start),
+            Event::End(TagEnd::Paragraph | TagEnd::Item) => {
+                let Some(start) = starts.pop() else {
+                    continue;
+                };
+                let block = &markdown[start..range.end];
+                let last_line = block.trim_end().lines().last().unwrap_or("");
+                if split_block_id(last_line).is_some_and(|(_, found)| found == id) {
+                    return Some(block);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::markdown::{render, testing, EmbeddedNote};
+
+    /// A context rendering the note `id`, in which every note of `notes` is
+    /// published and loaded
+    fn context(id: &str, notes: &[(&str, &str)]) -> RenderContext {
+        let paths: Vec<(&str, &str)> = notes.iter().map(|(id, _)| (*id, *id)).collect();
+        let mut context = testing::context(&paths);
+        context.id = Some(id.to_string());
+        for (id, content) in notes {
+            context.sources.insert(
+                id.to_string(),
+                EmbeddedNote {
+                    content: content.to_string(),
+                    attachments: Vec::new(),
+                },
+            );
+        }
+        context
+    }
+
+    #[test]
+    fn finds_heading_sections() {
+        let markdown = "# Top\nintro\n## Moon\nphases\n### Full\nbright\n## Sun\nhot\n";
+        assert_eq!(
+            section(markdown, "Moon"),
+            Some("## Moon\nphases\n### Full\nbright\n")
+        );
+        assert_eq!(
+            section(markdown, "Top#Moon#Full"),
+            Some("### Full\nbright\n")
+        );
+        assert_eq!(section(markdown, "Sun"), Some("## Sun\nhot\n"));
+        assert_eq!(section(markdown, "Stars"), None);
+    }
+
+    #[test]
+    fn finds_block_sections() {
+        let markdown = "first\n\nquoted line ^quote\n\n- one\n- two ^item\n";
+        assert_eq!(section(markdown, "^quote"), Some("quoted line ^quote\n"));
+        assert_eq!(section(markdown, "^item"), Some("- two ^item\n"));
+        assert_eq!(section(markdown, "^missing"), None);
+    }
+
+    #[test]
+    fn embeds_sections_of_other_notes() {
+        let context = context(
+            "post",
+            &[("post", ""), ("moon", "# Moon\nphases\n# Sun\nhot\n")],
+        );
+        let html = render("before\n\n![[moon#Sun]]\n\nafter", &context);
+        assert!(html.contains("<div class=\"transclusion\">"), "{}", html);
+        assert!(html.contains("hot"), "{}", html);
+        assert!(!html.contains("phases"), "{}", html);
+        assert!(html.starts_with("<p>before</p>"), "{}", html);
+        assert!(html.ends_with("<p>after</p>\n"), "{}", html);
+    }
+
+    #[test]
+    fn links_instead_of_embedding_cycles() {
+        let context = context(
+            "first",
+            &[
+                ("first", "![[second]]"),
+                ("second", "second says ![[first]]"),
+            ],
+        );
+        let html = render("![[second]]", &context);
+        assert_eq!(
+            html.matches("<div class=\"transclusion\">").count(),
+            1,
+            "{}",
+            html
+        );
+        assert!(
+            html.contains("second says <a class=\"wikilink\" href=\"/doll/first/\">first</a>"),
+            "{}",
+            html
+        );
+    }
+
+    #[test]
+    fn links_instead_of_embedding_itself() {
+        let context = context("self", &[("self", "![[self]]")]);
+        assert_eq!(
+            render("![[self]]", &context),
+            "<p><a class=\"wikilink\" href=\"/doll/self/\">self</a></p>\n"
+        );
+    }
+}
@@ -0,0 +1,365 @@
+use super::{anchors::slugify, encode_segment, Note, RenderContext};
+use crate::{
+    error::Result,
+    storage::{PostStore, PostSummary},
+};
+use handlebars::html_escape;
+use pulldown_cmark::{Event, Tag, TagEnd};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// How long a user's [`NoteIndex`] is kept before it is built again, which
+/// picks up posts written by the command line
+const NOTE_INDEX_LIFETIME: Duration = Duration::from_secs(60);
+
+/// A parsed `[[target#anchor|alias]]`
+pub struct Wikilink<'a> {
+    /// The note linked to; empty for a link within the same note
+    pub target: &'a str,
+    /// A heading, `Heading#Subheading`, or a block id starting with `^`
+    pub anchor: Option<&'a str>,
+    pub alias: Option<&'a str>,
+}
+
+impl<'a> Wikilink<'a> {
+    /// Parses the text between `[[` and `]]`
+    pub fn parse(inner: &'a str) -> Option<Self> {
+        let (link, alias) = match inner.split_once('|') {
+            Some((link, alias)) => (link, Some(alias.trim()).filter(|a| !a.is_empty())),
+            None => (inner, None),
+        };
+        let (target, anchor) = match link.split_once('#') {
+            Some((target, anchor)) => (target.trim(), Some(anchor.trim())),
+            None => (link.trim(), None),
+        };
+        let anchor = anchor.filter(|a| !a.is_empty());
+
+        if target.is_empty() && anchor.is_none() {
+            return None;
+        }
+        Some(Self {
+            target,
+            anchor,
+            alias,
+        })
+    }
+
+    /// The URL fragment of the anchor, without the `#`
+    pub fn fragment(&self) -> Option<String> {
+        let anchor = self.anchor?;
+        if let Some(block) = anchor.strip_prefix('^') {
+            return Some(format!("^{}", block));
+        }
+        // Nested headings link to the innermost one
+        let heading = anchor.rsplit('#').next()?;
+        Some(slugify(heading)).filter(|slug| !slug.is_empty())
+    }
+
+    /// The alias, or the target and anchor as Obsidian shows them
+    pub fn label(&self) -> String {
+        if let Some(alias) = self.alias {
+            return alias.to_string();
+        }
+        let mut parts: Vec<&str> = Vec::new();
+        if !self.target.is_empty() {
+            parts.push(self.target);
+        }
+        if let Some(anchor) = self.anchor {
+            parts.extend(anchor.split('#').map(str::trim).filter(|p| !p.is_empty()));
+        }
+        parts.join(" > ")
+    }
+}
+
+/// A user's posts by the names wikilinks refer to them with
+#[derive(Default)]
+pub struct NoteIndex {
+    /// Vault paths without the `.md` extension
+    by_path: HashMap<String, String>,
+    /// Note file names and post names
+    by_name: HashMap<String, String>,
+}
+
+/// Obsidian matches links case-insensitively and with or without `.md`
+fn normalize(name: &str) -> String {
+    let name = name.trim().replace('\\', "/").to_lowercase();
+    let name = name.trim_start_matches('/');
+    name.strip_suffix(".md").unwrap_or(name).to_string()
+}
+
+impl NoteIndex {
+    pub fn new(posts: &[PostSummary]) -> Self {
+        let mut posts: Vec<&PostSummary> = posts.iter().collect();
+        // Resolve ambiguous names the same way on every render
+        posts.sort_by(|a, b| a.id.cmp(&b.id));
+
+        let mut index = Self::default();
+        for post in posts {
+            let path = normalize(&post.path);
+            let file_name = path.rsplit('/').next().unwrap_or(&path).to_string();
+            index
+                .by_name
+                .entry(file_name)
+                .or_insert_with(|| post.id.clone());
+            index
+                .by_name
+                .entry(normalize(&post.name))
+                .or_insert_with(|| post.id.clone());
+            index.by_path.entry(path).or_insert_with(|| post.id.clone());
+        }
+        index
+    }
+
+    /// The id of the post `target` names, by vault path or by note name
+    pub fn resolve(&self, target: &str) -> Option<&str> {
+        let target = normalize(target);
+        self.by_path
+            .get(&target)
+            .or_else(|| self.by_name.get(&target))
+            .map(String::as_str)
+    }
+}
+
+/// Each user's [`NoteIndex`], kept between renders so a page view does not
+/// have to list every post of its author. Anything that publishes,
+/// unpublishes or restores a post must [`NoteIndexes::forget`] its user.
+#[derive(Clone, Default)]
+pub struct NoteIndexes {
+    inner: Arc<Mutex<IndexCache>>,
+}
+
+#[derive(Default)]
+struct IndexCache {
+    indexes: HashMap<String, (Instant, Arc<NoteIndex>)>,
+    /// Changes on every [`NoteIndexes::forget`], so an index built from a
+    /// listing taken before then is not kept
+    generation: u64,
+}
+
+impl NoteIndexes {
+    pub async fn get(&self, posts: &dyn PostStore, username: &str) -> Result<Arc<NoteIndex>> {
+        let generation = {
+            let cache = self.inner.lock().unwrap();
+            if let Some((built, index)) = cache.indexes.get(username) {
+                if built.elapsed() < NOTE_INDEX_LIFETIME {
+                    return Ok(index.clone());
+                }
+            }
+            cache.generation
+        };
+
+        let index = Arc::new(NoteIndex::new(&posts.list(username).await?));
+        let mut cache = self.inner.lock().unwrap();
+        if cache.generation == generation {
+            cache
+                .indexes
+                .insert(username.to_string(), (Instant::now(), index.clone()));
+        }
+        Ok(index)
+    }
+
+    pub fn forget(&self, username: &str) {
+        let mut cache = self.inner.lock().unwrap();
+        cache.indexes.remove(username);
+        cache.generation += 1;
+    }
+}
+
+/// Replaces `[[wikilinks]]` in text with links to the posts they name.
+/// Embeds, written `![[...]]`, are left alone, and so is the text of
+/// markdown links and images, which cannot hold another link.
+pub fn link_wikilinks<'a>(
+    events: Vec<Event<'a>>,
+    note: &Note,
+    context: &RenderContext,
+) -> Vec<Event<'a>> {
+    let mut out = Vec::with_capacity(events.len());
+    let mut in_code_block = false;
+    let mut link_depth = 0usize;
+
+    for event in events {
+        match event {
+            Event::Start(Tag::CodeBlock(_)) => {
+                in_code_block = true;
+                out.push(event);
+            }
+            Event::End(TagEnd::CodeBlock) => {
+                in_code_block = false;
+                out.push(event);
+            }
+            Event::Start(Tag::Link { .. } | Tag::Image { .. }) => {
+                link_depth += 1;
+                out.push(event);
+            }
+            Event::End(TagEnd::Link | TagEnd::Image) => {
+                link_depth = link_depth.saturating_sub(1);
+                out.push(event);
+            }
+            Event::Text(text) if !in_code_block && link_depth == 0 => {
+                split_wikilinks(&text, note, context, &mut out)
+            }
+            _ => out.push(event),
+        }
+    }
+    out
+}
+
+fn split_wikilinks<'a>(text: &str, note: &Note, context: &RenderContext, out: &mut Vec<Event<'a>>) {
+    let mut last = 0;
+    let mut pos = 0;
+    while let Some(found) = text[pos..].find("[[") {
+        let start = pos + found;
+        let Some(length) = text[start + 2..].find("]]") else {
+            break;
+        };
+        let inner = &text[start + 2..start + 2 + length];
+        pos = start + 2;
+
+        if text[..start].ends_with('!') || inner.contains(['[', '\n']) {
+            continue;
+        }
+        let Some(link) = Wikilink::parse(inner) else {
+            continue;
+        };
+
+        if start > last {
+            out.push(Event::Text(text[last..start].to_string().into()));
+        }
+        out.push(Event::InlineHtml(render_link(&link, note, context).into()));
+        last = start + 2 + length + 2;
+        pos = last;
+    }
+    if last < text.len() {
+        out.push(Event::Text(text[last..].to_string().into()));
+    }
+}
+
+pub(super) fn render_link(link: &Wikilink, note: &Note, context: &RenderContext) -> String {
+    let fragment = link
+        .fragment()
+        .map(|fragment| format!("#{}", fragment))
+        .unwrap_or_default();
+
+    let href = if link.target.is_empty() {
+        Some(fragment)
+    } else if let Some(name) = note.attachment(link.target) {
+        Some(note.attachment_url(name))
+    } else {
+        context.notes.resolve(link.target).map(|id| {
+            format!(
+                "/{}/{}/{}",
+                encode_segment(&context.username),
+                encode_segment(id),
+                fragment
+            )
+        })
+    };
+
+    let label = html_escape(&link.label());
+    match href {
+        Some(href) => format!(
+            "<a class=\"wikilink\" href=\"{}\">{}</a>",
+            html_escape(&href),
+            label
+        ),
+        None => format!(
+            "<span class=\"wikilink wikilink-unresolved\">{}</span>",
+            label
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        markdown::{
+            render,
+            testing::{context, render_alone},
+        },
+        models::{metadata::Metadata, post::Post},
+        storage::fs::{FsBlobStore, FsPostStore},
+    };
+
+    fn post(id: &str) -> Post {
+        Post {
+            name: id.to_string(),
+            path: format!("{}.md", id),
+            metadata: Metadata {
+                id: Some(id.to_string()),
+                extra: HashMap::new(),
+            },
+            content: String::new(),
+            attachments: None,
+            attachment_hashes: None,
+        }
+    }
+
+    #[test]
+    fn links_resolved_notes() {
+        let context = context(&[("abc123", "Notes/Moon Phases.md")]);
+        assert_eq!(
+            render("[[Moon Phases]]", &context),
+            "<p><a class=\"wikilink\" href=\"/doll/abc123/\">Moon Phases</a></p>\n"
+        );
+        assert_eq!(
+            render("[[notes/moon phases.md#Full Moon|full]]", &context),
+            "<p><a class=\"wikilink\" href=\"/doll/abc123/#full-moon\">full</a></p>\n"
+        );
+        assert_eq!(
+            render("[[Moon Phases#^quote]]", &context),
+            "<p><a class=\"wikilink\" href=\"/doll/abc123/#^quote\">Moon Phases &gt; ^quote</a></p>\n"
+        );
+    }
+
+    #[test]
+    fn marks_unresolved_notes() {
+        assert_eq!(
+            render_alone("see [[Salt & Pepper]]"),
+            "<p>see <span class=\"wikilink wikilink-unresolved\">Salt &amp; Pepper</span></p>\n"
+        );
+        assert_eq!(
+            render_alone("`[[Missing]]`"),
+            "<p><code>[[Missing]]</code></p>\n"
+        );
+    }
+
+    #[test]
+    fn leaves_link_text_alone() {
+        let context = context(&[("abc123", "Other.md")]);
+        assert_eq!(
+            render("[see [[Other]]](http://x)", &context),
+            "<p><a href=\"http://x\">see [[Other]]</a></p>\n"
+        );
+        assert_eq!(
+            render("![an [[Other]] picture](pic.png) and [[Other]]", &context),
+            "<p><img src=\"pic.png\" alt=\"an [[Other]] picture\" /> and \
+             <a class=\"wikilink\" href=\"/doll/abc123/\">Other</a></p>\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn forgetting_a_user_picks_up_new_posts() {
+        let dir = std::env::temp_dir().join(format!("dollpublish-notes-{}", uuid::Uuid::new_v4()));
+        let blobs = Arc::new(FsBlobStore::new(&dir));
+        let posts = FsPostStore::new(dir.clone(), blobs, None);
+        let notes = NoteIndexes::default();
+
+        posts.save("doll", "first", &post("first")).await.unwrap();
+        let index = notes.get(&posts, "doll").await.unwrap();
+        assert_eq!(index.resolve("first"), Some("first"));
+
+        posts.save("doll", "second", &post("second")).await.unwrap();
+        let index = notes.get(&posts, "doll").await.unwrap();
+        assert_eq!(index.resolve("second"), None);
+
+        notes.forget("doll");
+        let index = notes.get(&posts, "doll").await.unwrap();
+        assert_eq!(index.resolve("second"), Some("second"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
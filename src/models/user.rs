@@ -1,101 +1,746 @@
 use crate::error::{AppError, Result};
+use crate::storage::UserStore;
+use crate::utils::session::Session;
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use notify::{Event, RecursiveMode, Watcher};
+use rand::RngCore;
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, fs, path::PathBuf, sync::Arc};
-use tokio::sync::Mutex;
-use uuid::Uuid;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use subtle::ConstantTimeEq;
+use tokio::sync::RwLock;
 
+/// How many hex characters of a key's unsalted SHA-256 are stored to find
+/// it, which tells nothing about the key itself
+const KEY_LOOKUP_LEN: usize = 16;
+
+/// How often key uses noted while authenticating are written to the store
+const LAST_USED_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long to wait after the user store changes before reloading it
+const RELOAD_DELAY: Duration = Duration::from_millis(200);
+
+/// Something an API key may be allowed to do
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    /// Publish, republish, unpublish and restore posts, and upload attachments
+    Publish,
+    /// Read posts, drafts, revisions and the trash through the API
+    Read,
+    /// Read and replace `template.html` and `index.html`
+    Template,
+}
+
+impl Scope {
+    pub fn all() -> BTreeSet<Scope> {
+        BTreeSet::from([Scope::Publish, Scope::Read, Scope::Template])
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Scope::Publish => "publish",
+            Scope::Read => "read",
+            Scope::Template => "template",
+        };
+        f.write_str(name)
+    }
+}
+
+/// An API key, stored only as a salted SHA-256 of the key itself
 #[derive(Serialize, Deserialize, Clone)]
+pub struct ApiKey {
+    lookup: String,
+    salt: String,
+    hash: String,
+    /// Keys from before scopes existed keep full access
+    #[serde(default = "Scope::all")]
+    pub scopes: BTreeSet<Scope>,
+    /// Seconds since the unix epoch
+    pub created: u64,
+    pub last_used: Option<u64>,
+}
+
+/// What is shown about a key after it was created
+#[derive(Serialize)]
+pub struct KeySummary {
+    pub name: String,
+    pub scopes: BTreeSet<Scope>,
+    pub created: u64,
+    pub last_used: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
 pub struct User {
-    pub api_key: String,
+    /// API keys by name
+    #[serde(default)]
+    pub keys: HashMap<String, ApiKey>,
+    /// Disabled users keep their posts and keys, but cannot authenticate
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub disabled: bool,
+    /// Argon2 hash of the password for browser logins, if one is set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    password: Option<String>,
+    /// Plaintext key written by older versions, hashed into `keys` on load
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    api_key: Option<String>,
+    /// Carried by every session cookie of the user, and changed to end them
+    /// all
+    #[serde(default)]
+    pub session_epoch: u64,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
 pub struct Users {
     pub users: HashMap<String, User>,
-    #[serde(skip)]
-    data_dir: Option<PathBuf>,
+    index: KeyIndex,
+    store: Arc<dyn UserStore>,
+    /// The store's version when the users were loaded, if it has versions
+    version: Option<u64>,
+    /// When each key was last used, by username and key name, since this
+    /// was last written to the store
+    uses: Mutex<HashMap<(String, String), u64>>,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn salted_hash(salt: &str, key: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(salt.as_bytes());
+    hasher.update(key.as_bytes());
+    hex::encode(hasher.finalize())
+}
+
+/// Generates a new random API key
+pub fn generate_key() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    format!("dp_{}", hex::encode(bytes))
+}
+
+impl ApiKey {
+    pub fn new(key: &str, scopes: BTreeSet<Scope>) -> Self {
+        let mut salt = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut salt);
+        let salt = hex::encode(salt);
+
+        Self {
+            lookup: lookup_of(key),
+            hash: salted_hash(&salt, key),
+            salt,
+            scopes,
+            created: now(),
+            last_used: None,
+        }
+    }
+
+    fn matches(&self, key: &str) -> bool {
+        salted_hash(&self.salt, key)
+            .as_bytes()
+            .ct_eq(self.hash.as_bytes())
+            .into()
+    }
+}
+
+impl User {
+    /// Summaries of the user's keys, sorted by name
+    pub fn key_summaries(&self) -> Vec<KeySummary> {
+        let mut keys: Vec<KeySummary> = self
+            .keys
+            .iter()
+            .map(|(name, key)| KeySummary {
+                name: name.clone(),
+                scopes: key.scopes.clone(),
+                created: key.created,
+                last_used: key.last_used,
+            })
+            .collect();
+        keys.sort_by(|a, b| a.name.cmp(&b.name));
+        keys
+    }
+}
+
+/// Usernames become the first path segment of every post URL, so they are
+/// kept to characters that are safe there and cannot shadow `/_moon/` and
+/// the other built-in routes
+fn check_username(username: &str) -> Result<()> {
+    let valid = username
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+        && username
+            .chars()
+            .next()
+            .is_some_and(|c| c.is_ascii_alphanumeric());
+    if !valid {
+        return Err(AppError::BadRequest(format!(
+            "Invalid username: {}",
+            username
+        )));
+    }
+    Ok(())
+}
+
+/// A key that was found for a request
+pub struct VerifiedKey {
+    pub username: String,
+    pub name: String,
+    pub scopes: BTreeSet<Scope>,
+    /// Tells this key apart from any other key that has had its name
+    pub id: String,
+}
+
+/// The user and key name of every key, by [`lookup_of`] the key
+type KeyIndex = HashMap<String, Vec<(String, String)>>;
+
+fn lookup_of(key: &str) -> String {
+    let mut lookup = hex::encode(Sha256::digest(key.as_bytes()));
+    lookup.truncate(KEY_LOOKUP_LEN);
+    lookup
+}
+
+/// A hash of the users that does not depend on the order of their maps
+fn fingerprint(users: &HashMap<String, User>) -> Result<Vec<u8>> {
+    let value = serde_json::to_value(users).map_err(|e| AppError::Internal(e.to_string()))?;
+    Ok(Sha256::digest(value.to_string().as_bytes()).to_vec())
+}
+
+fn build_index(users: &HashMap<String, User>) -> KeyIndex {
+    let mut index = KeyIndex::new();
+    for (username, user) in users {
+        for (name, key) in &user.keys {
+            index
+                .entry(key.lookup.clone())
+                .or_default()
+                .push((username.clone(), name.clone()));
+        }
+    }
+    index
 }
 
 impl Users {
-    pub async fn load_or_create(data_dir: &PathBuf) -> Result<Arc<Mutex<Self>>> {
-        if !data_dir.exists() {
-            fs::create_dir_all(data_dir).map_err(|e| AppError::Internal(e.to_string()))?;
-        }
-
-        let users_path = data_dir.join("users.json");
-        if !users_path.exists() {
-            let mut users = HashMap::new();
-            users.insert(
-                "default".to_string(),
-                User {
-                    api_key: Uuid::new_v4().to_string(),
-                },
-            );
-            let users_data = Users {
+    pub async fn load_or_create(store: Arc<dyn UserStore>) -> Result<Arc<RwLock<Self>>> {
+        let version = store.version().await?;
+        if let Some(users) = store.load().await? {
+            let mut users_data = Users {
                 users,
-                data_dir: Some(data_dir.clone()),
+                index: KeyIndex::new(),
+                store,
+                version,
+                uses: Mutex::new(HashMap::new()),
             };
-            users_data.save().await?;
-
-            return Ok(Arc::new(Mutex::new(users_data)));
+            if users_data.hash_plaintext_keys() {
+                users_data.save().await?;
+            }
+            users_data.index = build_index(&users_data.users);
+            return Ok(Arc::new(RwLock::new(users_data)));
         }
 
-        let content =
-            fs::read_to_string(&users_path).map_err(|e| AppError::Internal(e.to_string()))?;
-        let mut users: Users =
-            serde_json::from_str(&content).map_err(|e| AppError::Internal(e.to_string()))?;
+        let key = generate_key();
+        let mut user = User::default();
+        user.keys
+            .insert("default".to_string(), ApiKey::new(&key, Scope::all()));
+        println!("Created user default with API key {}", key);
 
-        users.data_dir = Some(data_dir.clone());
-        Ok(Arc::new(Mutex::new(users)))
+        let mut users = HashMap::new();
+        users.insert("default".to_string(), user);
+        let mut users_data = Users {
+            users,
+            index: KeyIndex::new(),
+            store,
+            version,
+            uses: Mutex::new(HashMap::new()),
+        };
+        users_data.save().await?;
+
+        Ok(Arc::new(RwLock::new(users_data)))
     }
 
-    async fn save(&self) -> Result<()> {
-        let data_dir = self
-            .data_dir
-            .as_ref()
-            .ok_or_else(|| AppError::Internal("data_dir not set".to_string()))?;
+    /// Writes the users to the store and refreshes the key index
+    async fn save(&mut self) -> Result<()> {
+        self.index = build_index(&self.users);
+        self.store.save(&self.users).await
+    }
 
-        fs::write(
-            data_dir.join("users.json"),
-            serde_json::to_string_pretty(&self).unwrap(),
-        )
-        .map_err(|e| AppError::Internal(e.to_string()))?;
+    /// Reads the users back from the store, returning whether they differ
+    /// from those in memory
+    async fn reload(&mut self) -> Result<bool> {
+        // The store may change for reasons of its own, such as SQLite
+        // writing posts to the same database
+        let version = self.store.version().await?;
+        if version.is_some() && version == self.version {
+            return Ok(false);
+        }
+        self.version = version;
 
-        Ok(())
+        let Some(users) = self.store.load().await? else {
+            return Ok(false);
+        };
+        // Most changes are this process's own saves, such as recording key
+        // uses, which are already in memory
+        if fingerprint(&users)? == fingerprint(&self.users)? {
+            return Ok(false);
+        }
+        self.users = users;
+        if self.hash_plaintext_keys() {
+            self.save().await?;
+        }
+        self.index = build_index(&self.users);
+        Ok(true)
     }
 
-    async fn reload(&mut self) -> Result<()> {
-        let data_dir = self
-            .data_dir
-            .as_ref()
-            .ok_or_else(|| AppError::Internal("data_dir not set".to_string()))?;
+    /// Reloads the users whenever the store is changed outside this process,
+    /// for example by the command line or by hand
+    pub fn watch(users: Arc<RwLock<Self>>, path: PathBuf) -> Result<()> {
+        let dir = path
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .unwrap_or(Path::new("."))
+            .to_path_buf();
+        let file_name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
 
-        let content = fs::read_to_string(data_dir.join("users.json"))
+        // Editors and SQLite write through sibling files such as
+        // `users.json.tmp` or `dollpublish.db-wal`, so watch the directory
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut watcher = notify::recommended_watcher(move |event: notify::Result<Event>| {
+            let Ok(event) = event else { return };
+            let relevant = event.paths.iter().any(|p| {
+                p.file_name()
+                    .is_some_and(|name| name.to_string_lossy().starts_with(&file_name))
+            });
+            if relevant && !event.kind.is_access() {
+                let _ = tx.send(());
+            }
+        })
+        .map_err(|e| AppError::Internal(e.to_string()))?;
+        watcher
+            .watch(&dir, RecursiveMode::NonRecursive)
             .map_err(|e| AppError::Internal(e.to_string()))?;
-        let reloaded: Users =
-            serde_json::from_str(&content).map_err(|e| AppError::Internal(e.to_string()))?;
 
-        self.users = reloaded.users;
+        tokio::spawn(async move {
+            // The watcher stops when dropped
+            let _watcher = watcher;
+            while rx.recv().await.is_some() {
+                // Let a burst of writes settle before reading them
+                tokio::time::sleep(RELOAD_DELAY).await;
+                while rx.try_recv().is_ok() {}
+
+                if let Err(e) = users.write().await.reload().await {
+                    eprintln!("Failed to reload users: {}", e);
+                }
+            }
+        });
         Ok(())
     }
 
-    pub async fn verify_credentials(&mut self, api_key: &str, api_secret: &str) -> Option<String> {
-        // First try with current data
-        for (username, user) in self.users.iter() {
-            if user.api_key == api_key || user.api_key == api_secret {
-                return Some(username.clone());
+    /// Replaces plaintext keys from older versions with a hashed key named
+    /// `default`, returning whether anything changed
+    fn hash_plaintext_keys(&mut self) -> bool {
+        let mut changed = false;
+        for user in self.users.values_mut() {
+            if let Some(key) = user.api_key.take() {
+                user.keys
+                    .insert("default".to_string(), ApiKey::new(&key, Scope::all()));
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    fn find_key(&self, key: &str) -> Option<VerifiedKey> {
+        if key.is_empty() {
+            return None;
+        }
+
+        let candidates = self.index.get(&lookup_of(key))?;
+        for (username, name) in candidates {
+            let Some(user) = self.users.get(username).filter(|user| !user.disabled) else {
+                continue;
+            };
+            let Some(api_key) = user.keys.get(name).filter(|k| k.matches(key)) else {
+                continue;
+            };
+
+            return Some(VerifiedKey {
+                username: username.clone(),
+                name: name.clone(),
+                scopes: api_key.scopes.clone(),
+                id: api_key.salt.clone(),
+            });
+        }
+        None
+    }
+
+    /// Finds the key given as either the API key or the API secret
+    pub fn verify_credentials(&self, api_key: &str, api_secret: &str) -> Option<VerifiedKey> {
+        self.find_key(api_key).or_else(|| self.find_key(api_secret))
+    }
+
+    /// Notes that a key was used without writing to the store, which
+    /// [`Users::spawn_record_uses`] does later
+    pub fn note_use(&self, username: &str, name: &str) {
+        self.uses
+            .lock()
+            .unwrap()
+            .insert((username.to_string(), name.to_string()), now());
+    }
+
+    /// Writes the key uses noted since the last call to the store
+    async fn record_uses(&mut self) -> Result<()> {
+        let uses = std::mem::take(&mut *self.uses.lock().unwrap());
+        let mut changed = false;
+        for ((username, name), time) in uses {
+            if let Some(key) = self
+                .users
+                .get_mut(&username)
+                .and_then(|user| user.keys.get_mut(&name))
+            {
+                key.last_used = Some(time);
+                changed = true;
             }
         }
+        if changed {
+            self.save().await?;
+        }
+        Ok(())
+    }
 
-        // If not found, reload and try again
-        if let Ok(()) = self.reload().await {
-            for (username, user) in self.users.iter() {
-                if user.api_key == api_key || user.api_key == api_secret {
-                    return Some(username.clone());
+    /// Writes noted key uses to the store every [`LAST_USED_INTERVAL`] for
+    /// the lifetime of the server, so authenticating never has to
+    pub fn spawn_record_uses(users: Arc<RwLock<Self>>) {
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(LAST_USED_INTERVAL);
+            loop {
+                ticker.tick().await;
+                if users.read().await.uses.lock().unwrap().is_empty() {
+                    continue;
+                }
+                if let Err(e) = users.write().await.record_uses().await {
+                    eprintln!("Failed to record API key use: {}", e);
                 }
             }
+        });
+    }
+
+    /// Adds a new named key for `username`, returning the key itself, which
+    /// is not stored anywhere
+    pub async fn create_key(
+        &mut self,
+        username: &str,
+        name: &str,
+        scopes: BTreeSet<Scope>,
+    ) -> Result<String> {
+        let user = self.users.get_mut(username).ok_or(AppError::NotFound)?;
+        if name.is_empty() {
+            return Err(AppError::BadRequest(
+                "Key name must not be empty".to_string(),
+            ));
+        }
+        if scopes.is_empty() {
+            return Err(AppError::BadRequest(
+                "A key needs at least one scope".to_string(),
+            ));
+        }
+        if user.keys.contains_key(name) {
+            return Err(AppError::Conflict(format!(
+                "A key named {} already exists",
+                name
+            )));
         }
 
-        None
+        let key = generate_key();
+        user.keys
+            .insert(name.to_string(), ApiKey::new(&key, scopes));
+        self.save().await?;
+        Ok(key)
+    }
+
+    pub async fn revoke_key(&mut self, username: &str, name: &str) -> Result<()> {
+        let user = self.users.get_mut(username).ok_or(AppError::NotFound)?;
+        user.keys.remove(name).ok_or(AppError::NotFound)?;
+        self.save().await
+    }
+
+    /// Creates a user with a single key named `default` holding every scope,
+    /// returning that key
+    pub async fn create_user(&mut self, username: &str) -> Result<String> {
+        check_username(username)?;
+        if self.users.contains_key(username) {
+            return Err(AppError::Conflict(format!(
+                "User {} already exists",
+                username
+            )));
+        }
+
+        // A fresh epoch keeps sessions of a deleted user with the same name
+        // from carrying over
+        let key = generate_key();
+        let mut user = User {
+            session_epoch: rand::random(),
+            ..User::default()
+        };
+        user.keys
+            .insert("default".to_string(), ApiKey::new(&key, Scope::all()));
+        self.users.insert(username.to_string(), user);
+        self.save().await?;
+        Ok(key)
+    }
+
+    pub async fn set_disabled(&mut self, username: &str, disabled: bool) -> Result<()> {
+        let user = self.users.get_mut(username).ok_or(AppError::NotFound)?;
+        user.disabled = disabled;
+        self.save().await
+    }
+
+    /// Removes a user and all their keys; their posts are left in place
+    pub async fn delete_user(&mut self, username: &str) -> Result<()> {
+        self.users.remove(username).ok_or(AppError::NotFound)?;
+        self.save().await
+    }
+
+    /// Replaces a key with a new one of the same name and scopes, returning
+    /// the new key
+    pub async fn rotate_key(&mut self, username: &str, name: &str) -> Result<String> {
+        let user = self.users.get_mut(username).ok_or(AppError::NotFound)?;
+        let old = user.keys.get(name).ok_or(AppError::NotFound)?;
+
+        let key = generate_key();
+        let rotated = ApiKey::new(&key, old.scopes.clone());
+        user.keys.insert(name.to_string(), rotated);
+        self.save().await?;
+        Ok(key)
+    }
+
+    /// Adds users that do not exist yet, returning the names of those added
+    pub async fn import_users(&mut self, users: HashMap<String, User>) -> Result<Vec<String>> {
+        let mut added = Vec::new();
+        for (username, user) in users {
+            if !self.users.contains_key(&username) {
+                self.users.insert(username.clone(), user);
+                added.push(username);
+            }
+        }
+        if self.hash_plaintext_keys() || !added.is_empty() {
+            self.save().await?;
+        }
+        added.sort();
+        Ok(added)
+    }
+
+    /// Sets the password used for browser logins, or removes it
+    pub async fn set_password(&mut self, username: &str, password: Option<&str>) -> Result<()> {
+        let user = self.users.get_mut(username).ok_or(AppError::NotFound)?;
+        user.password = match password {
+            Some("") => {
+                return Err(AppError::BadRequest(
+                    "Password must not be empty".to_string(),
+                ))
+            }
+            Some(password) => {
+                let salt = SaltString::generate(&mut OsRng);
+                let hash = Argon2::default()
+                    .hash_password(password.as_bytes(), &salt)
+                    .map_err(|e| AppError::Internal(e.to_string()))?;
+                Some(hash.to_string())
+            }
+            None => None,
+        };
+        user.session_epoch = rand::random();
+        self.save().await
+    }
+
+    /// Ends every browser session of `username`
+    pub async fn end_sessions(&mut self, username: &str) -> Result<()> {
+        let user = self.users.get_mut(username).ok_or(AppError::NotFound)?;
+        user.session_epoch = rand::random();
+        self.save().await
+    }
+
+    /// The epoch a new session of `username` carries
+    pub fn session_epoch(&self, username: &str) -> u64 {
+        self.users
+            .get(username)
+            .map(|user| user.session_epoch)
+            .unwrap_or_default()
+    }
+
+    /// Checks a browser login password
+    pub fn verify_password(&self, username: &str, password: &str) -> bool {
+        let Some(hash) = self
+            .users
+            .get(username)
+            .filter(|user| !user.disabled)
+            .and_then(|user| user.password.as_deref())
+        else {
+            return false;
+        };
+
+        PasswordHash::new(hash).is_ok_and(|hash| {
+            Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok()
+        })
+    }
+
+    /// The scopes a browser session still has: those of the key it was
+    /// started with, or every scope for a password login, as long as the
+    /// user, that same key or a password still exists and the session was
+    /// not ended
+    pub fn session_scopes(&self, session: &Session) -> Option<BTreeSet<Scope>> {
+        let user = self
+            .users
+            .get(&session.username)
+            .filter(|user| !user.disabled && user.session_epoch == session.epoch)?;
+        match &session.key {
+            Some(name) => user
+                .keys
+                .get(name)
+                .filter(|key| session.key_id.as_ref() == Some(&key.salt))
+                .map(|key| key.scopes.clone()),
+            None => user.password.as_ref().map(|_| Scope::all()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::fs::FsUserStore;
+
+    #[tokio::test]
+    async fn finds_keys_without_storing_them() {
+        let dir = std::env::temp_dir().join(format!("dollpublish-users-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let users = Users::load_or_create(Arc::new(FsUserStore::new(&dir)))
+            .await
+            .unwrap();
+        let key = users.write().await.create_user("doll").await.unwrap();
+
+        let stored = std::fs::read_to_string(dir.join("users.json")).unwrap();
+        let secret = key.trim_start_matches("dp_");
+        assert!(!stored.contains(&secret[..8]));
+
+        let users = users.read().await;
+        let found = users.verify_credentials(&key, "").unwrap();
+        assert_eq!(found.username, "doll");
+        assert_eq!(found.name, "default");
+        assert!(users.verify_credentials(&generate_key(), "").is_none());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn records_noted_uses_later() {
+        let dir = std::env::temp_dir().join(format!("dollpublish-users-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let store = Arc::new(FsUserStore::new(&dir));
+        let users = Users::load_or_create(store.clone()).await.unwrap();
+        let mut users = users.write().await;
+        users.create_user("doll").await.unwrap();
+
+        users.note_use("doll", "default");
+        let stored = store.load().await.unwrap().unwrap();
+        assert!(stored["doll"].keys["default"].last_used.is_none());
+
+        users.record_uses().await.unwrap();
+        let stored = store.load().await.unwrap().unwrap();
+        assert!(stored["doll"].keys["default"].last_used.is_some());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn reloads_only_changes_made_elsewhere() {
+        let dir = std::env::temp_dir().join(format!("dollpublish-users-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let store = Arc::new(FsUserStore::new(&dir));
+        let users = Users::load_or_create(store.clone()).await.unwrap();
+        let mut users = users.write().await;
+        users.create_user("doll").await.unwrap();
+        users.note_use("doll", "default");
+        users.record_uses().await.unwrap();
+        assert!(!users.reload().await.unwrap());
+
+        let mut stored = store.load().await.unwrap().unwrap();
+        stored.insert("moth".to_string(), User::default());
+        store.save(&stored).await.unwrap();
+        assert!(users.reload().await.unwrap());
+        assert!(users.users.contains_key("moth"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn session(users: &Users, key: Option<&str>) -> Session {
+        let key = key.map(|key| users.verify_credentials(key, "").unwrap());
+        Session {
+            username: "doll".to_string(),
+            key: key.as_ref().map(|key| key.name.clone()),
+            key_id: key.map(|key| key.id),
+            expires: 0,
+            csrf: String::new(),
+            epoch: users.session_epoch("doll"),
+        }
+    }
+
+    #[tokio::test]
+    async fn ends_only_the_sessions_that_should_end() {
+        let dir = std::env::temp_dir().join(format!("dollpublish-users-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let users = Users::load_or_create(Arc::new(FsUserStore::new(&dir)))
+            .await
+            .unwrap();
+        let mut users = users.write().await;
+        let key = users.create_user("doll").await.unwrap();
+        let spare = users
+            .create_key("doll", "spare", Scope::all())
+            .await
+            .unwrap();
+        users.set_password("doll", Some("hunter2")).await.unwrap();
+
+        let with_key = session(&users, Some(&key));
+        let with_spare = session(&users, Some(&spare));
+        let with_password = session(&users, None);
+        users.revoke_key("doll", "spare").await.unwrap();
+        assert!(users.session_scopes(&with_spare).is_none());
+        assert!(users.session_scopes(&with_key).is_some());
+
+        // A new key with the old name does not bring the session back
+        let spare = users
+            .create_key("doll", "spare", Scope::all())
+            .await
+            .unwrap();
+        assert!(users.session_scopes(&with_spare).is_none());
+        let with_spare = session(&users, Some(&spare));
+        users.rotate_key("doll", "default").await.unwrap();
+        assert!(users.session_scopes(&with_key).is_none());
+        assert!(users.session_scopes(&with_spare).is_some());
+        assert!(users.session_scopes(&with_password).is_some());
+
+        users.set_password("doll", Some("hunter3")).await.unwrap();
+        assert!(users.session_scopes(&with_spare).is_none());
+        assert!(users.session_scopes(&with_password).is_none());
+
+        let with_password = session(&users, None);
+        users.end_sessions("doll").await.unwrap();
+        assert!(users.session_scopes(&with_password).is_none());
+
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 }
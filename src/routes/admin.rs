@@ -0,0 +1,262 @@
+use crate::{
+    error::Result,
+    models::user::{KeySummary, Scope},
+    routes::moon::handlers::{CreateKeyRequest, CreateKeyResponse},
+    utils::auth::authenticate_admin,
+    AppState,
+};
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    routing::{delete, get, post},
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+
+pub fn admin_routes() -> Router<AppState> {
+    Router::new()
+        .route("/_admin/users", get(list_users).post(create_user))
+        .route("/_admin/users/:username", delete(delete_user))
+        .route("/_admin/users/:username/disable", post(disable_user))
+        .route("/_admin/users/:username/enable", post(enable_user))
+        .route("/_admin/users/:username/keys", post(create_key))
+        .route("/_admin/users/:username/keys/:name", delete(revoke_key))
+}
+
+#[derive(Serialize)]
+struct UserSummary {
+    username: String,
+    disabled: bool,
+    keys: Vec<KeySummary>,
+}
+
+#[derive(Deserialize)]
+struct CreateUserRequest {
+    username: String,
+}
+
+#[derive(Serialize)]
+struct CreateUserResponse {
+    username: String,
+    /// The user's `default` key, only ever shown in this response
+    key: String,
+}
+
+async fn list_users(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<UserSummary>>> {
+    authenticate_admin(&headers, state.admin_key.as_deref())?;
+    let users = state.users.read().await;
+
+    let mut summaries: Vec<UserSummary> = users
+        .users
+        .iter()
+        .map(|(username, user)| UserSummary {
+            username: username.clone(),
+            disabled: user.disabled,
+            keys: user.key_summaries(),
+        })
+        .collect();
+    summaries.sort_by(|a, b| a.username.cmp(&b.username));
+    Ok(Json(summaries))
+}
+
+async fn create_user(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(data): Json<CreateUserRequest>,
+) -> Result<(StatusCode, Json<CreateUserResponse>)> {
+    authenticate_admin(&headers, state.admin_key.as_deref())?;
+    let key = state
+        .users
+        .write()
+        .await
+        .create_user(&data.username)
+        .await?;
+
+    Ok((
+        StatusCode::CREATED,
+        Json(CreateUserResponse {
+            username: data.username,
+            key,
+        }),
+    ))
+}
+
+async fn delete_user(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(username): Path<String>,
+) -> Result<StatusCode> {
+    authenticate_admin(&headers, state.admin_key.as_deref())?;
+    state.users.write().await.delete_user(&username).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn disable_user(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(username): Path<String>,
+) -> Result<StatusCode> {
+    authenticate_admin(&headers, state.admin_key.as_deref())?;
+    state
+        .users
+        .write()
+        .await
+        .set_disabled(&username, true)
+        .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn enable_user(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(username): Path<String>,
+) -> Result<StatusCode> {
+    authenticate_admin(&headers, state.admin_key.as_deref())?;
+    state
+        .users
+        .write()
+        .await
+        .set_disabled(&username, false)
+        .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn create_key(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(username): Path<String>,
+    Json(data): Json<CreateKeyRequest>,
+) -> Result<Json<CreateKeyResponse>> {
+    authenticate_admin(&headers, state.admin_key.as_deref())?;
+    let key = state
+        .users
+        .write()
+        .await
+        .create_key(
+            &username,
+            &data.name,
+            data.scopes.unwrap_or_else(Scope::all),
+        )
+        .await?;
+
+    Ok(Json(CreateKeyResponse {
+        name: data.name,
+        key,
+    }))
+}
+
+async fn revoke_key(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path((username, name)): Path<(String, String)>,
+) -> Result<StatusCode> {
+    authenticate_admin(&headers, state.admin_key.as_deref())?;
+    state
+        .users
+        .write()
+        .await
+        .revoke_key(&username, &name)
+        .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::{TestServer, ADMIN_KEY};
+    use axum::{
+        body::Body,
+        http::{header, Request, StatusCode},
+    };
+
+    fn admin_request(method: &str, uri: &str, admin_key: &str, body: &str) -> Request<Body> {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("admin-key", admin_key)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn needs_the_admin_key() {
+        let server = TestServer::new().await;
+        for key in ["", "wrong", server.key.as_str()] {
+            let response = server
+                .send(admin_request("GET", "/_admin/users", key, ""))
+                .await;
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        }
+        let response = server
+            .send(admin_request("GET", "/_admin/users", ADMIN_KEY, ""))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // Without a key configured, the admin API does not exist
+        let server = TestServer::with_state(|state| state.admin_key = None).await;
+        let response = server
+            .send(admin_request("GET", "/_admin/users", ADMIN_KEY, ""))
+            .await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn refuses_duplicate_users() {
+        let server = TestServer::new().await;
+        let create = || admin_request("POST", "/_admin/users", ADMIN_KEY, r#"{"username":"moth"}"#);
+        assert_eq!(server.send(create()).await.status(), StatusCode::CREATED);
+        assert_eq!(server.send(create()).await.status(), StatusCode::CONFLICT);
+    }
+
+    #[tokio::test]
+    async fn disabling_a_user_shuts_out_keys_and_sessions() {
+        let server = TestServer::new().await;
+        let cookie = server.login().await;
+        let with_key = || {
+            Request::get("/_moon/list")
+                .header(header::AUTHORIZATION, format!("Bearer {}", server.key))
+                .body(Body::empty())
+                .unwrap()
+        };
+        let with_session = || {
+            Request::get("/_session")
+                .header(header::COOKIE, &cookie)
+                .body(Body::empty())
+                .unwrap()
+        };
+        assert_eq!(server.send(with_key()).await.status(), StatusCode::OK);
+        assert_eq!(server.send(with_session()).await.status(), StatusCode::OK);
+
+        let response = server
+            .send(admin_request(
+                "POST",
+                "/_admin/users/doll/disable",
+                ADMIN_KEY,
+                "",
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(
+            server.send(with_key()).await.status(),
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            server.send(with_session()).await.status(),
+            StatusCode::UNAUTHORIZED
+        );
+
+        let response = server
+            .send(admin_request(
+                "POST",
+                "/_admin/users/doll/enable",
+                ADMIN_KEY,
+                "",
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(server.send(with_key()).await.status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,367 @@
+use crate::{
+    error::{AppError, Result},
+    markdown::RenderContext,
+    models::{metadata::Metadata, post::Post, user::Scope},
+    routes::files::write_user_file,
+    storage::hash_blob,
+    utils::{
+        audit::AuditEntry,
+        auth::{check_csrf, session_auth, Auth},
+        client::ClientAddress,
+        session::Session,
+        template::Templates,
+        time::civil_date,
+    },
+    AppState,
+};
+use axum::{
+    extract::{Path, State},
+    http::{header, HeaderMap},
+    response::{Html, IntoResponse, Redirect, Response},
+    routing::{get, post},
+    Form, Router,
+};
+use handlebars::html_escape;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+pub fn dashboard_routes() -> Router<AppState> {
+    Router::new()
+        .route("/_dashboard", get(dashboard))
+        .route("/_dashboard/posts/:id/markdown", get(download_markdown))
+        .route("/_dashboard/posts/:id/unpublish", post(unpublish))
+        .route(
+            "/_dashboard/template",
+            get(template_editor).post(save_template),
+        )
+        .route("/_dashboard/template/preview", post(preview_template))
+}
+
+#[derive(Deserialize)]
+struct CsrfForm {
+    csrf: String,
+}
+
+#[derive(Deserialize)]
+struct TemplateForm {
+    csrf: String,
+    template: String,
+    /// Post to preview the template with; a sample post when empty
+    #[serde(default)]
+    id: String,
+}
+
+/// The session of a request, or a redirect to the login form without one
+async fn logged_in(
+    state: &AppState,
+    headers: &HeaderMap,
+) -> std::result::Result<(Session, Auth), Response> {
+    match session_auth(headers, state).await {
+        Ok(found) => Ok(found),
+        Err(AppError::AuthenticationError) => Err(Redirect::to("/_login").into_response()),
+        Err(e) => Err(e.into_response()),
+    }
+}
+
+/// Formats seconds since the unix epoch as `YYYY-MM-DD HH:MM` in UTC
+fn format_timestamp(seconds: u64) -> String {
+    let (year, month, day) = civil_date((seconds / 86400) as i64);
+    let minutes = (seconds % 86400) / 60;
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}",
+        year,
+        month,
+        day,
+        minutes / 60,
+        minutes % 60
+    )
+}
+
+fn page(title: &str, body: &str) -> Html<String> {
+    Html(format!(
+        "<!DOCTYPE html><html lang=\"en\"><head><meta charset=\"UTF-8\">\
+         <meta name=\"viewport\" content=\"width=device-width, initial-scale=1.0\">\
+         <link rel=\"stylesheet\" href=\"https://cdn.jsdelivr.net/npm/sakura.css/css/sakura.css\">\
+         <title>{}</title></head><body>{}</body></html>",
+        html_escape(title),
+        body
+    ))
+}
+
+fn logout_form(session: &Session) -> String {
+    let csrf = html_escape(&session.csrf);
+    format!(
+        "<form method=\"post\" action=\"/_logout\"><input type=\"hidden\" name=\"csrf\" value=\"{}\">\
+         <button type=\"submit\">Log out</button></form>\
+         <form method=\"post\" action=\"/_logout/everywhere\"><input type=\"hidden\" name=\"csrf\" value=\"{}\">\
+         <button type=\"submit\">Log out everywhere</button></form>",
+        csrf, csrf
+    )
+}
+
+async fn dashboard(State(state): State<AppState>, headers: HeaderMap) -> Result<Response> {
+    let (session, auth) = match logged_in(&state, &headers).await {
+        Ok(found) => found,
+        Err(redirect) => return Ok(redirect),
+    };
+    let username = auth.require(Scope::Read)?;
+
+    let mut posts = state.posts.list(&username).await?;
+    posts.sort_by_key(|post| std::cmp::Reverse(post.modified));
+
+    let rows: String = posts
+        .iter()
+        .map(|post| {
+            let id = html_escape(&post.id);
+            format!(
+                "<tr><td><a href=\"/{user}/{id}/\">{name}</a></td><td><code>{id}</code></td>\
+                 <td>{path}</td><td>{modified}</td><td>\
+                 <a href=\"/{user}/{id}/\">View</a> \
+                 <a href=\"/_dashboard/posts/{id}/markdown\">Markdown</a> \
+                 <form method=\"post\" action=\"/_dashboard/posts/{id}/unpublish\" style=\"display:inline\" \
+                 onsubmit=\"return confirm('Unpublish {name_js}?')\">\
+                 <input type=\"hidden\" name=\"csrf\" value=\"{csrf}\">\
+                 <button type=\"submit\">Unpublish</button></form></td></tr>",
+                user = html_escape(&username),
+                id = id,
+                name = html_escape(&post.name),
+                name_js = html_escape(&post.name.replace(['\\', '\''], "")),
+                path = html_escape(&post.path),
+                modified = format_timestamp(post.modified),
+                csrf = html_escape(&session.csrf),
+            )
+        })
+        .collect();
+
+    let table = if posts.is_empty() {
+        "<p>You have not published anything yet.</p>".to_string()
+    } else {
+        format!(
+            "<table><thead><tr><th>Title</th><th>Id</th><th>Path</th><th>Modified (UTC)</th>\
+             <th>Actions</th></tr></thead><tbody>{}</tbody></table>",
+            rows
+        )
+    };
+
+    let body = format!(
+        "<h1>{}</h1><p><a href=\"/_dashboard/template\">Edit template</a></p>{}{}",
+        html_escape(&username),
+        table,
+        logout_form(&session)
+    );
+    Ok(page("Dashboard", &body).into_response())
+}
+
+async fn download_markdown(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> Result<Response> {
+    let (_, auth) = match logged_in(&state, &headers).await {
+        Ok(found) => found,
+        Err(redirect) => return Ok(redirect),
+    };
+    let username = auth.require(Scope::Read)?;
+    let post = state.posts.load_document(&username, &id).await?;
+
+    // Named after the note in the vault, which is the last part of its path
+    let filename = post
+        .path
+        .rsplit('/')
+        .next()
+        .filter(|name| !name.is_empty())
+        .map(str::to_string)
+        .unwrap_or_else(|| format!("{}.md", id))
+        .replace(['"', '\\', '\r', '\n'], "");
+
+    Ok((
+        [
+            (
+                header::CONTENT_TYPE,
+                "text/markdown; charset=utf-8".to_string(),
+            ),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}\"", filename),
+            ),
+        ],
+        post.content,
+    )
+        .into_response())
+}
+
+async fn unpublish(
+    State(state): State<AppState>,
+    ClientAddress(ip): ClientAddress,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+    Form(form): Form<CsrfForm>,
+) -> Result<Response> {
+    let (session, auth) = match logged_in(&state, &headers).await {
+        Ok(found) => found,
+        Err(redirect) => return Ok(redirect),
+    };
+    check_csrf(&session, Some(&form.csrf))?;
+    let username = auth.require(Scope::Publish)?;
+
+    let removed = state.posts.load_document(&username, &id).await?;
+    state.posts.delete(&username, &id).await?;
+    state.notes.forget(&username);
+    state
+        .audit
+        .record(AuditEntry {
+            id: Some(id),
+            hash: Some(hash_blob(removed.content.as_bytes())),
+            ..AuditEntry::new(&auth, ip, "unpublish")
+        })
+        .await;
+
+    Ok(Redirect::to("/_dashboard").into_response())
+}
+
+async fn template_editor(State(state): State<AppState>, headers: HeaderMap) -> Result<Response> {
+    let (session, auth) = match logged_in(&state, &headers).await {
+        Ok(found) => found,
+        Err(redirect) => return Ok(redirect),
+    };
+    let username = auth.require(Scope::Template)?;
+
+    let template = Templates::user_template(&state.data_dir, &username);
+    let options: String = state
+        .posts
+        .list(&username)
+        .await?
+        .iter()
+        .map(|post| {
+            format!(
+                "<option value=\"{}\">{}</option>",
+                html_escape(&post.id),
+                html_escape(&post.name)
+            )
+        })
+        .collect();
+
+    let body = format!(
+        "<h1>Template</h1><p><a href=\"/_dashboard\">Back to posts</a></p>\
+         <form id=\"editor\" method=\"post\" action=\"/_dashboard/template\">\
+         <input type=\"hidden\" name=\"csrf\" value=\"{csrf}\">\
+         <p><label>Preview with <select name=\"id\"><option value=\"\">Sample post</option>{options}</select></label></p>\
+         <textarea name=\"template\" rows=\"20\" style=\"width:100%;font-family:monospace\">{template}</textarea>\
+         <p><button type=\"submit\">Save template</button></p></form>\
+         <iframe id=\"preview\" sandbox style=\"width:100%;height:40em;border:1px solid\"></iframe>\
+         <script>\
+         const form = document.getElementById('editor');\
+         const preview = document.getElementById('preview');\
+         let timer;\
+         function refresh() {{\
+           fetch('/_dashboard/template/preview', {{ method: 'POST', body: new URLSearchParams(new FormData(form)) }})\
+             .then(response => response.text())\
+             .then(html => {{ preview.srcdoc = html; }});\
+         }}\
+         form.addEventListener('input', () => {{ clearTimeout(timer); timer = setTimeout(refresh, 300); }});\
+         refresh();\
+         </script>",
+        csrf = html_escape(&session.csrf),
+        options = options,
+        template = html_escape(&template),
+    );
+    Ok(page("Template", &body).into_response())
+}
+
+/// A post to preview templates with when the user has none or picks none
+fn sample_post() -> Post {
+    Post {
+        name: "Sample post".to_string(),
+        path: "Sample post.md".to_string(),
+        metadata: Metadata {
+            id: None,
+            extra: HashMap::from([(
+                "description".to_string(),
+                serde_json::Value::String("A post to preview your template with".to_string()),
+            )]),
+        },
+        content:
+            "This is a **sample post**.\n\n- It has a list\n- And [a link](https://example.com)\n"
+                .to_string(),
+        attachments: None,
+        attachment_hashes: None,
+    }
+}
+
+async fn preview_template(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Form(form): Form<TemplateForm>,
+) -> Result<Response> {
+    let (session, auth) = match logged_in(&state, &headers).await {
+        Ok(found) => found,
+        Err(redirect) => return Ok(redirect),
+    };
+    check_csrf(&session, Some(&form.csrf))?;
+    let username = auth.require(Scope::Template)?;
+
+    let (post, attachments) = if form.id.is_empty() {
+        (sample_post(), Vec::new())
+    } else {
+        (
+            state.posts.load_document(&username, &form.id).await?,
+            state.posts.attachment_names(&username, &form.id).await?,
+        )
+    };
+    let context = RenderContext::load(
+        state.posts.as_ref(),
+        &state.notes,
+        &username,
+        &post,
+        attachments,
+    )
+    .await?;
+    let content = post.render_content(&context);
+
+    let html = state
+        .templates
+        .render_template(&form.template, &username, &post, &content)
+        .unwrap_or_else(|e| {
+            format!(
+                "<html><body><h1>Template error</h1><pre>{}</pre></body></html>",
+                html_escape(&e)
+            )
+        });
+    Ok(Html(html).into_response())
+}
+
+async fn save_template(
+    State(state): State<AppState>,
+    ClientAddress(ip): ClientAddress,
+    headers: HeaderMap,
+    Form(form): Form<TemplateForm>,
+) -> Result<Response> {
+    let (session, auth) = match logged_in(&state, &headers).await {
+        Ok(found) => found,
+        Err(redirect) => return Ok(redirect),
+    };
+    check_csrf(&session, Some(&form.csrf))?;
+    let username = auth.require(Scope::Template)?;
+
+    write_user_file(
+        &state.data_dir,
+        &username,
+        "template.html",
+        form.template.as_bytes(),
+    )?;
+    state
+        .posts
+        .user_file_written(&username, "template.html")
+        .await?;
+    state
+        .audit
+        .record(AuditEntry {
+            file: Some("template.html".to_string()),
+            hash: Some(hash_blob(form.template.as_bytes())),
+            ..AuditEntry::new(&auth, ip, "put_file")
+        })
+        .await;
+
+    Ok(Redirect::to("/_dashboard/template").into_response())
+}
@@ -1,4 +1,10 @@
-use crate::{error::AppError, utils::auth::authenticate, AppState};
+use crate::{
+    error::AppError,
+    models::user::Scope,
+    storage::hash_blob,
+    utils::{audit::AuditEntry, auth::authenticate, client::ClientAddress},
+    AppState,
+};
 use axum::{
     body::Bytes,
     extract::{Path, State},
@@ -9,7 +15,7 @@ use axum::{
 };
 use std::fs;
 
-const ALLOWED_FILES: [&str; 2] = ["template.html", "index.html"];
+pub const ALLOWED_FILES: [&str; 2] = ["template.html", "index.html"];
 
 pub fn file_routes() -> Router<AppState> {
     Router::new()
@@ -17,12 +23,29 @@ pub fn file_routes() -> Router<AppState> {
         .route("/_files/:filename", put(put_file))
 }
 
+/// Writes one of the [`ALLOWED_FILES`] into the user's directory
+pub fn write_user_file(
+    data_dir: &std::path::Path,
+    username: &str,
+    filename: &str,
+    body: &[u8],
+) -> Result<(), AppError> {
+    let user_dir = data_dir.join(username);
+    fs::create_dir_all(&user_dir).map_err(|e| AppError::Internal(e.to_string()))?;
+
+    let file_path = user_dir.join(filename);
+    fs::write(&file_path, body).map_err(|e| AppError::Internal(e.to_string()))?;
+    Ok(())
+}
+
 async fn get_file(
     State(state): State<AppState>,
     headers: HeaderMap,
     Path(filename): Path<String>,
 ) -> Result<impl IntoResponse, AppError> {
-    let username = authenticate(&headers, &state.users).await?;
+    let username = authenticate(&headers, &state)
+        .await?
+        .require(Scope::Template)?;
 
     if !ALLOWED_FILES.contains(&filename.as_str()) {
         return Err(AppError::NotFound);
@@ -37,21 +60,67 @@ async fn get_file(
 
 async fn put_file(
     State(state): State<AppState>,
+    ClientAddress(ip): ClientAddress,
     headers: HeaderMap,
     Path(filename): Path<String>,
     body: Bytes,
 ) -> Result<impl IntoResponse, AppError> {
-    let username = authenticate(&headers, &state.users).await?;
+    let auth = authenticate(&headers, &state).await?;
+    let username = auth.require(Scope::Template)?;
 
     if !ALLOWED_FILES.contains(&filename.as_str()) {
         return Err(AppError::InvalidFile);
     }
 
-    let user_dir = state.data_dir.join(&username);
-    fs::create_dir_all(&user_dir).map_err(|e| AppError::Internal(e.to_string()))?;
-
-    let file_path = user_dir.join(&filename);
-    fs::write(&file_path, body).map_err(|e| AppError::Internal(e.to_string()))?;
+    write_user_file(&state.data_dir, &username, &filename, &body)?;
+    state.posts.user_file_written(&username, &filename).await?;
+    state
+        .audit
+        .record(AuditEntry {
+            file: Some(filename),
+            hash: Some(hash_blob(&body)),
+            ..AuditEntry::new(&auth, ip, "put_file")
+        })
+        .await;
 
     Ok(StatusCode::OK)
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{models::user::Scope, testing::TestServer};
+    use axum::{
+        body::Body,
+        http::{header, Method, Request, StatusCode},
+    };
+
+    fn template_request(method: Method, key: &str) -> Request<Body> {
+        Request::builder()
+            .method(method)
+            .uri("/_files/template.html")
+            .header(header::AUTHORIZATION, format!("Bearer {}", key))
+            .body(Body::from("{{{content}}}"))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn templates_need_the_template_scope() {
+        let server = TestServer::new().await;
+        let reader = server.key_with("reader", &[Scope::Read]).await;
+        let publisher = server.key_with("publisher", &[Scope::Publish]).await;
+        let designer = server.key_with("designer", &[Scope::Template]).await;
+
+        for key in [&reader, &publisher] {
+            for method in [Method::GET, Method::PUT] {
+                let response = server.send(template_request(method, key)).await;
+                assert_eq!(response.status(), StatusCode::FORBIDDEN);
+            }
+        }
+        assert!(!server.dir.join("doll").join("template.html").exists());
+
+        let response = server.send(template_request(Method::PUT, &designer)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = server.send(template_request(Method::GET, &designer)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
@@ -1,3 +1,6 @@
+pub mod admin;
+pub mod dashboard;
 pub mod files;
 pub mod moon;
+pub mod session;
 pub mod view;
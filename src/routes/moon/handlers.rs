@@ -1,21 +1,90 @@
 use crate::{
-    error::Result,
-    models::{metadata::Metadata, post::Post},
-    utils::auth::authenticate,
+    error::{AppError, Result},
+    models::{
+        metadata::Metadata,
+        post::Post,
+        user::{KeySummary, Scope, User},
+    },
+    storage::{
+        check_hash, hash_blob, AttachmentChanges, PostSummary, Revision, RevisionSummary,
+        TrashEntry,
+    },
+    utils::{audit::AuditEntry, auth::authenticate, client::ClientAddress, diff::unified_diff},
 };
 use axum::{
-    extract::{Path, State},
-    http::HeaderMap,
+    body::Body,
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
     Json,
 };
-use std::collections::HashMap;
+use futures_util::{future, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+use tokio_util::io::StreamReader;
+
+#[derive(Serialize)]
+pub struct PublishResponse {
+    #[serde(flatten)]
+    pub metadata: Metadata,
+    pub attachments: AttachmentChanges,
+}
+
+#[derive(Deserialize)]
+pub struct NegotiateRequest {
+    /// Attachment filename to SHA-256
+    pub attachments: HashMap<String, String>,
+}
+
+#[derive(Serialize)]
+pub struct NegotiateResponse {
+    /// Hashes that must be uploaded to `/_moon/blobs/:hash` before publishing
+    pub missing: Vec<String>,
+}
+
+/// Whether `username` may learn that a blob exists and publish it by hash.
+/// Only blobs their own posts reference, given as `referenced`, or that they
+/// uploaded themselves count, so nobody can probe for other users' files.
+fn knows_blob(
+    state: &crate::AppState,
+    username: &str,
+    referenced: &HashSet<String>,
+    hash: &str,
+) -> bool {
+    referenced.contains(hash) || state.uploads.contains(username, hash)
+}
+
+/// Treats attachments given by a hash `username` does not know as missing
+async fn check_attachment_hashes(
+    state: &crate::AppState,
+    username: &str,
+    post: &Post,
+) -> Result<()> {
+    let Some(hashes) = &post.attachment_hashes else {
+        return Ok(());
+    };
+    let referenced = state.posts.user_blobs(username).await?;
+    for hash in hashes.values() {
+        if !knows_blob(state, username, &referenced, hash) {
+            return Err(AppError::MissingBlob(hash.clone()));
+        }
+    }
+    Ok(())
+}
 
 pub async fn publish(
     State(state): State<crate::AppState>,
+    ClientAddress(ip): ClientAddress,
     headers: HeaderMap,
     Json(mut data): Json<Post>,
-) -> Result<Json<Metadata>> {
-    let username = authenticate(&headers, &state.users).await?;
+) -> Result<Json<PublishResponse>> {
+    let auth = authenticate(&headers, &state).await?;
+    let username = auth.require(Scope::Publish)?;
 
     let id = match data.metadata.id {
         Some(ref id) => id.clone(),
@@ -23,32 +92,72 @@ pub async fn publish(
     };
 
     data.metadata.id = Some(id.clone());
-    data.save(&state.data_dir, &username, &id).await?;
+    check_attachment_hashes(&state, &username, &data).await?;
+    let attachments = state.posts.save(&username, &id, &data).await?;
+    state.notes.forget(&username);
+    state
+        .audit
+        .record(AuditEntry {
+            id: Some(id),
+            hash: Some(hash_blob(data.content.as_bytes())),
+            ..AuditEntry::new(&auth, ip, "publish")
+        })
+        .await;
 
-    Ok(Json(data.metadata))
+    Ok(Json(PublishResponse {
+        metadata: data.metadata,
+        attachments,
+    }))
 }
 
 pub async fn republish(
     State(state): State<crate::AppState>,
+    ClientAddress(ip): ClientAddress,
     headers: HeaderMap,
     Path(id): Path<String>,
     Json(mut data): Json<Post>,
-) -> Result<Json<Metadata>> {
-    let username = authenticate(&headers, &state.users).await?;
+) -> Result<Json<PublishResponse>> {
+    let auth = authenticate(&headers, &state).await?;
+    let username = auth.require(Scope::Publish)?;
 
     data.metadata.id = Some(id.clone());
-    data.save(&state.data_dir, &username, &id).await?;
+    check_attachment_hashes(&state, &username, &data).await?;
+    let attachments = state.posts.save(&username, &id, &data).await?;
+    state.notes.forget(&username);
+    state
+        .audit
+        .record(AuditEntry {
+            id: Some(id),
+            hash: Some(hash_blob(data.content.as_bytes())),
+            ..AuditEntry::new(&auth, ip, "republish")
+        })
+        .await;
 
-    Ok(Json(data.metadata))
+    Ok(Json(PublishResponse {
+        metadata: data.metadata,
+        attachments,
+    }))
 }
 
 pub async fn unpublish(
     State(state): State<crate::AppState>,
+    ClientAddress(ip): ClientAddress,
     headers: HeaderMap,
     Path(id): Path<String>,
 ) -> Result<Json<Metadata>> {
-    let username = authenticate(&headers, &state.users).await?;
-    Post::delete(&state.data_dir, &username, &id).await?;
+    let auth = authenticate(&headers, &state).await?;
+    let username = auth.require(Scope::Publish)?;
+    let removed = state.posts.load_document(&username, &id).await?;
+    state.posts.delete(&username, &id).await?;
+    state.notes.forget(&username);
+    state
+        .audit
+        .record(AuditEntry {
+            id: Some(id),
+            hash: Some(hash_blob(removed.content.as_bytes())),
+            ..AuditEntry::new(&auth, ip, "unpublish")
+        })
+        .await;
 
     Ok(Json(Metadata {
         id: None,
@@ -61,7 +170,459 @@ pub async fn detail(
     headers: HeaderMap,
     Path(id): Path<String>,
 ) -> Result<Json<Post>> {
-    let username = authenticate(&headers, &state.users).await?;
-    let data = Post::load(&state.data_dir, &username, &id).await?;
+    let username = authenticate(&headers, &state).await?.require(Scope::Read)?;
+    let data = state.posts.load(&username, &id).await?;
     Ok(Json(data))
 }
+
+pub async fn list(
+    State(state): State<crate::AppState>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<PostSummary>>> {
+    let username = authenticate(&headers, &state).await?.require(Scope::Read)?;
+    let posts = state.posts.list(&username).await?;
+    Ok(Json(posts))
+}
+
+pub async fn negotiate(
+    State(state): State<crate::AppState>,
+    headers: HeaderMap,
+    Json(data): Json<NegotiateRequest>,
+) -> Result<Json<NegotiateResponse>> {
+    let username = authenticate(&headers, &state)
+        .await?
+        .require(Scope::Publish)?;
+
+    let referenced = state.posts.user_blobs(&username).await?;
+    let mut missing = Vec::new();
+    for hash in data.attachments.into_values() {
+        check_hash(&hash)?;
+        if missing.contains(&hash) {
+            continue;
+        }
+        if !knows_blob(&state, &username, &referenced, &hash) || !state.blobs.exists(&hash).await? {
+            missing.push(hash);
+        }
+    }
+
+    Ok(Json(NegotiateResponse { missing }))
+}
+
+pub async fn upload_blob(
+    State(state): State<crate::AppState>,
+    headers: HeaderMap,
+    Path(hash): Path<String>,
+    body: Body,
+) -> Result<StatusCode> {
+    let username = authenticate(&headers, &state)
+        .await?
+        .require(Scope::Publish)?;
+    check_hash(&hash)?;
+
+    // Blobs are only skipped when the user could have known they exist
+    let referenced = state.posts.user_blobs(&username).await?;
+    if knows_blob(&state, &username, &referenced, &hash) && state.blobs.exists(&hash).await? {
+        return Ok(StatusCode::OK);
+    }
+
+    let limit = state.max_blob_bytes;
+    let declared = headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    if declared.is_some_and(|length| length > limit) {
+        return Err(AppError::PayloadTooLarge(limit));
+    }
+
+    // Chunked uploads give no length up front, so stop reading once they
+    // pass the limit
+    let received = Arc::new(AtomicU64::new(0));
+    let counted = received.clone();
+    let stream = body
+        .into_data_stream()
+        .map_err(std::io::Error::other)
+        .and_then(move |chunk| {
+            let total =
+                counted.fetch_add(chunk.len() as u64, Ordering::Relaxed) + chunk.len() as u64;
+            future::ready(if total > limit {
+                Err(std::io::Error::other("blob is too large"))
+            } else {
+                Ok(chunk)
+            })
+        });
+    let stored = state
+        .blobs
+        .put_stream(&hash, Box::new(StreamReader::new(stream)))
+        .await;
+    if received.load(Ordering::Relaxed) > limit {
+        return Err(AppError::PayloadTooLarge(limit));
+    }
+    stored?;
+    state.uploads.record(&username, &hash);
+    Ok(StatusCode::CREATED)
+}
+
+pub async fn revisions(
+    State(state): State<crate::AppState>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> Result<Json<Vec<RevisionSummary>>> {
+    let username = authenticate(&headers, &state).await?.require(Scope::Read)?;
+    let revisions = state.posts.revisions(&username, &id).await?;
+    Ok(Json(revisions))
+}
+
+pub async fn revision(
+    State(state): State<crate::AppState>,
+    headers: HeaderMap,
+    Path((id, number)): Path<(String, u64)>,
+) -> Result<Json<Revision>> {
+    let username = authenticate(&headers, &state).await?.require(Scope::Read)?;
+    let revision = state.posts.revision(&username, &id, number).await?;
+    Ok(Json(revision))
+}
+
+pub async fn diff(
+    State(state): State<crate::AppState>,
+    headers: HeaderMap,
+    Path((id, from, to)): Path<(String, u64, u64)>,
+) -> Result<String> {
+    let username = authenticate(&headers, &state).await?.require(Scope::Read)?;
+    let from = state.posts.revision(&username, &id, from).await?;
+    let to = state.posts.revision(&username, &id, to).await?;
+    Ok(unified_diff(&from, &to))
+}
+
+pub async fn restore(
+    State(state): State<crate::AppState>,
+    ClientAddress(ip): ClientAddress,
+    headers: HeaderMap,
+    Path((id, number)): Path<(String, u64)>,
+) -> Result<Json<PublishResponse>> {
+    let auth = authenticate(&headers, &state).await?;
+    let username = auth.require(Scope::Publish)?;
+    let revision = state.posts.revision(&username, &id, number).await?;
+
+    let post = revision.to_post(&id);
+    let attachments = state.posts.save(&username, &id, &post).await?;
+    state.notes.forget(&username);
+    state
+        .audit
+        .record(AuditEntry {
+            id: Some(id),
+            hash: Some(hash_blob(post.content.as_bytes())),
+            ..AuditEntry::new(&auth, ip, "restore")
+        })
+        .await;
+
+    Ok(Json(PublishResponse {
+        metadata: post.metadata,
+        attachments,
+    }))
+}
+
+pub async fn trash(
+    State(state): State<crate::AppState>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<TrashEntry>>> {
+    let username = authenticate(&headers, &state).await?.require(Scope::Read)?;
+    let trash = state.posts.trash(&username).await?;
+    Ok(Json(trash))
+}
+
+pub async fn restore_trashed(
+    State(state): State<crate::AppState>,
+    ClientAddress(ip): ClientAddress,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> Result<Json<Metadata>> {
+    let auth = authenticate(&headers, &state).await?;
+    let username = auth.require(Scope::Publish)?;
+    state.posts.restore_trashed(&username, &id).await?;
+    state.notes.forget(&username);
+    state
+        .audit
+        .record(AuditEntry {
+            id: Some(id.clone()),
+            ..AuditEntry::new(&auth, ip, "restore_trashed")
+        })
+        .await;
+
+    Ok(Json(Metadata {
+        id: Some(id),
+        extra: HashMap::new(),
+    }))
+}
+
+pub async fn purge_trashed(
+    State(state): State<crate::AppState>,
+    ClientAddress(ip): ClientAddress,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> Result<Json<Metadata>> {
+    let auth = authenticate(&headers, &state).await?;
+    let username = auth.require(Scope::Publish)?;
+    state.posts.purge_trashed(&username, &id).await?;
+    state
+        .audit
+        .record(AuditEntry {
+            id: Some(id),
+            ..AuditEntry::new(&auth, ip, "purge")
+        })
+        .await;
+
+    Ok(Json(Metadata {
+        id: None,
+        extra: HashMap::new(),
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct CreateKeyRequest {
+    pub name: String,
+    /// Defaults to every scope
+    pub scopes: Option<BTreeSet<Scope>>,
+}
+
+#[derive(Serialize)]
+pub struct CreateKeyResponse {
+    pub name: String,
+    /// Only ever shown in this response
+    pub key: String,
+}
+
+pub async fn keys(
+    State(state): State<crate::AppState>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<KeySummary>>> {
+    let username = authenticate(&headers, &state).await?.require_all()?;
+    let users = state.users.read().await;
+
+    let keys = users
+        .users
+        .get(&username)
+        .map(User::key_summaries)
+        .unwrap_or_default();
+    Ok(Json(keys))
+}
+
+pub async fn create_key(
+    State(state): State<crate::AppState>,
+    headers: HeaderMap,
+    Json(data): Json<CreateKeyRequest>,
+) -> Result<Json<CreateKeyResponse>> {
+    let username = authenticate(&headers, &state).await?.require_all()?;
+    let key = state
+        .users
+        .write()
+        .await
+        .create_key(
+            &username,
+            &data.name,
+            data.scopes.unwrap_or_else(Scope::all),
+        )
+        .await?;
+
+    Ok(Json(CreateKeyResponse {
+        name: data.name,
+        key,
+    }))
+}
+
+pub async fn revoke_key(
+    State(state): State<crate::AppState>,
+    headers: HeaderMap,
+    Path(name): Path<String>,
+) -> Result<StatusCode> {
+    let username = authenticate(&headers, &state).await?.require_all()?;
+    state
+        .users
+        .write()
+        .await
+        .revoke_key(&username, &name)
+        .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Entries per page of the audit log unless the request asks for fewer
+const AUDIT_PAGE_SIZE: usize = 50;
+
+#[derive(Deserialize)]
+pub struct AuditQuery {
+    #[serde(default)]
+    pub offset: usize,
+    pub limit: Option<usize>,
+}
+
+#[derive(Serialize)]
+pub struct AuditResponse {
+    pub entries: Vec<AuditEntry>,
+    /// Offset of the next, older page, if there is one
+    pub next_offset: Option<usize>,
+}
+
+pub async fn audit(
+    State(state): State<crate::AppState>,
+    headers: HeaderMap,
+    Query(query): Query<AuditQuery>,
+) -> Result<Json<AuditResponse>> {
+    let username = authenticate(&headers, &state).await?.require(Scope::Read)?;
+
+    let limit = query
+        .limit
+        .unwrap_or(AUDIT_PAGE_SIZE)
+        .clamp(1, AUDIT_PAGE_SIZE);
+    let (entries, more) = state.audit.entries(&username, query.offset, limit).await?;
+    let next_offset = more.then(|| query.offset + entries.len());
+
+    Ok(Json(AuditResponse {
+        entries,
+        next_offset,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{models::user::Scope, testing::TestServer};
+    use axum::{
+        body::{to_bytes, Body},
+        http::{header, Request, StatusCode},
+    };
+    use serde_json::json;
+
+    fn upload(key: &str, hash: &str, body: Body) -> Request<Body> {
+        Request::put(format!("/_moon/blobs/{}", hash))
+            .header(header::AUTHORIZATION, format!("Bearer {}", key))
+            .body(body)
+            .unwrap()
+    }
+
+    fn post_json(key: &str, uri: &str, body: serde_json::Value) -> Request<Body> {
+        Request::post(uri)
+            .header(header::AUTHORIZATION, format!("Bearer {}", key))
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+
+    async fn missing(server: &TestServer, key: &str, hash: &str) -> bool {
+        let request = post_json(
+            key,
+            "/_moon/negotiate",
+            json!({ "attachments": { "a.png": hash } }),
+        );
+        let response = server.send(request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        body["missing"] == json!([hash])
+    }
+
+    #[tokio::test]
+    async fn rejects_blobs_over_the_limit() {
+        let server = TestServer::with_state(|state| state.max_blob_bytes = 1000).await;
+        let data = vec![7u8; 2000];
+        let hash = crate::storage::hash_blob(&data);
+
+        let response = server
+            .send(upload(&server.key, &hash, Body::from(data.clone())))
+            .await;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        // Without a length up front
+        let chunks: Vec<Result<Vec<u8>, std::io::Error>> =
+            data.chunks(300).map(|c| Ok(c.to_vec())).collect();
+        let body = Body::from_stream(futures_util::stream::iter(chunks));
+        let response = server.send(upload(&server.key, &hash, body)).await;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(!server.state.blobs.exists(&hash).await.unwrap());
+
+        let small = vec![7u8; 1000];
+        let hash = crate::storage::hash_blob(&small);
+        let response = server
+            .send(upload(&server.key, &hash, Body::from(small)))
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn only_reveals_blobs_a_user_uploaded_or_published() {
+        let server = TestServer::new().await;
+        let other = server
+            .state
+            .users
+            .write()
+            .await
+            .create_user("moth")
+            .await
+            .unwrap();
+        let data = b"a secret picture".to_vec();
+        let hash = crate::storage::hash_blob(&data);
+        let response = server
+            .send(upload(&server.key, &hash, Body::from(data.clone())))
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert!(!missing(&server, &server.key, &hash).await);
+
+        // Someone else must upload it too, and may not publish it by hash
+        // until they have
+        assert!(missing(&server, &other, &hash).await);
+        let post = json!({
+            "name": "Moth",
+            "path": "moth.md",
+            "metadata": { "id": "moth" },
+            "content": "hi",
+            "attachments": null,
+            "attachment_hashes": { "a.png": hash },
+        });
+        let response = server
+            .send(post_json(&other, "/_moon/publish", post.clone()))
+            .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let response = server.send(upload(&other, &hash, Body::from(data))).await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert!(!missing(&server, &other, &hash).await);
+        let response = server.send(post_json(&other, "/_moon/publish", post)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn read_keys_cannot_publish() {
+        let server = TestServer::new().await;
+        let reader = server.key_with("reader", &[Scope::Read]).await;
+        let post = json!({
+            "name": "A",
+            "path": "a.md",
+            "metadata": { "id": "a" },
+            "content": "hi",
+            "attachments": null,
+        });
+
+        let response = server
+            .send(post_json(&reader, "/_moon/publish", post.clone()))
+            .await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let response = server
+            .send(post_json(
+                &reader,
+                "/_moon/negotiate",
+                json!({ "attachments": {} }),
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let hash = crate::storage::hash_blob(b"x");
+        let response = server.send(upload(&reader, &hash, Body::from("x"))).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let response = server
+            .send(post_json(&server.key, "/_moon/publish", post))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let request = Request::get("/_moon/list")
+            .header(header::AUTHORIZATION, format!("Bearer {}", reader))
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(server.send(request).await.status(), StatusCode::OK);
+    }
+}
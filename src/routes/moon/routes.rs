@@ -1,5 +1,6 @@
 use super::handlers;
-use axum::routing::{get, post};
+use axum::extract::DefaultBodyLimit;
+use axum::routing::{delete, get, post, put};
 use axum::Router;
 
 pub fn moon_routes() -> Router<crate::AppState> {
@@ -8,4 +9,23 @@ pub fn moon_routes() -> Router<crate::AppState> {
         .route("/_moon/publish/:id", post(handlers::republish))
         .route("/_moon/unpublish/:id", post(handlers::unpublish))
         .route("/_moon/detail/:id", get(handlers::detail))
+        .route("/_moon/list", get(handlers::list))
+        .route("/_moon/revisions/:id", get(handlers::revisions))
+        .route("/_moon/revisions/:id/:n", get(handlers::revision))
+        .route("/_moon/revisions/:id/:n/restore", post(handlers::restore))
+        .route("/_moon/diff/:id/:from/:to", get(handlers::diff))
+        .route("/_moon/trash", get(handlers::trash))
+        .route("/_moon/trash/:id/restore", post(handlers::restore_trashed))
+        .route("/_moon/trash/:id/purge", post(handlers::purge_trashed))
+        .route(
+            "/_moon/keys",
+            get(handlers::keys).post(handlers::create_key),
+        )
+        .route("/_moon/keys/:name", delete(handlers::revoke_key))
+        .route("/_moon/audit", get(handlers::audit))
+        .route("/_moon/negotiate", post(handlers::negotiate))
+        .route(
+            "/_moon/blobs/:hash",
+            put(handlers::upload_blob).layer(DefaultBodyLimit::disable()),
+        )
 }
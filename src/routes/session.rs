@@ -0,0 +1,203 @@
+use crate::{
+    error::Result,
+    utils::{
+        auth::{check_csrf, session_auth},
+        rate_limit::FailedAuthentication,
+    },
+    AppState,
+};
+use axum::{
+    extract::State,
+    http::{header, HeaderMap, StatusCode},
+    response::{Html, IntoResponse, Redirect, Response},
+    routing::{get, post},
+    Extension, Form, Json, Router,
+};
+use serde::{Deserialize, Serialize};
+
+pub fn session_routes() -> Router<AppState> {
+    Router::new()
+        .route("/_login", get(login_form).post(login))
+        .route("/_logout", post(logout))
+        .route("/_logout/everywhere", post(logout_everywhere))
+        .route("/_session", get(session))
+}
+
+#[derive(Deserialize)]
+struct LoginForm {
+    username: String,
+    /// An API key of the user, or their password
+    secret: String,
+}
+
+#[derive(Deserialize)]
+struct LogoutForm {
+    csrf: String,
+}
+
+#[derive(Serialize)]
+struct SessionResponse {
+    username: String,
+    /// Must be sent as `x-csrf-token` with API requests made with the session
+    csrf_token: String,
+    expires: u64,
+}
+
+fn login_page(error: Option<&str>) -> Html<String> {
+    let error = error
+        .map(|e| format!("<p>{}</p>", handlebars::html_escape(e)))
+        .unwrap_or_default();
+    Html(format!(
+        "<html><body><h1>Log in</h1>{}<form method=\"post\" action=\"/_login\">\
+         <p><label>Username <input name=\"username\" autocomplete=\"username\" required></label></p>\
+         <p><label>API key or password <input name=\"secret\" type=\"password\" \
+         autocomplete=\"current-password\" required></label></p>\
+         <p><button type=\"submit\">Log in</button></p></form></body></html>",
+        error
+    ))
+}
+
+async fn login_form() -> Html<String> {
+    login_page(None)
+}
+
+async fn login(State(state): State<AppState>, Form(form): Form<LoginForm>) -> Response {
+    let login = {
+        let users = state.users.read().await;
+        let key = match users
+            .verify_credentials(&form.secret, "")
+            .filter(|key| key.username == form.username)
+        {
+            Some(key) => Some(Some(key)),
+            None if users.verify_password(&form.username, &form.secret) => Some(None),
+            None => None,
+        };
+        key.map(|key| (key, users.session_epoch(&form.username)))
+    };
+
+    let Some((key, epoch)) = login else {
+        return (
+            StatusCode::UNAUTHORIZED,
+            Extension(FailedAuthentication),
+            login_page(Some("Wrong username, key or password")),
+        )
+            .into_response();
+    };
+
+    let (_, cookie) = state.sessions.create(&form.username, key.as_ref(), epoch);
+    ([(header::SET_COOKIE, cookie)], Redirect::to("/_dashboard")).into_response()
+}
+
+/// Logs this browser out; the user's other sessions are left alone
+async fn logout(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Form(form): Form<LogoutForm>,
+) -> Result<Response> {
+    if let Some(session) = state.sessions.current(&headers) {
+        check_csrf(&session, Some(&form.csrf))?;
+    }
+
+    Ok((
+        [(header::SET_COOKIE, state.sessions.clear_cookie())],
+        Redirect::to("/_login"),
+    )
+        .into_response())
+}
+
+/// Ends every session of the user, in any browser
+async fn logout_everywhere(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Form(form): Form<LogoutForm>,
+) -> Result<Response> {
+    let (session, _) = session_auth(&headers, &state).await?;
+    check_csrf(&session, Some(&form.csrf))?;
+    state
+        .users
+        .write()
+        .await
+        .end_sessions(&session.username)
+        .await?;
+
+    Ok((
+        [(header::SET_COOKIE, state.sessions.clear_cookie())],
+        Redirect::to("/_login"),
+    )
+        .into_response())
+}
+
+async fn session(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<SessionResponse>> {
+    let (session, _) = session_auth(&headers, &state).await?;
+
+    Ok(Json(SessionResponse {
+        username: session.username,
+        csrf_token: session.csrf,
+        expires: session.expires,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::{json, TestServer};
+    use axum::{
+        body::Body,
+        http::{header, Request, StatusCode},
+    };
+
+    /// Logs in with `doll`'s key, returning the cookie and CSRF token
+    async fn login(server: &TestServer) -> (String, String) {
+        let cookie = server.login().await;
+        let body = json(server.send(session_request(&cookie)).await).await;
+        (cookie, body["csrf_token"].as_str().unwrap().to_string())
+    }
+
+    fn session_request(cookie: &str) -> Request<Body> {
+        Request::get("/_session")
+            .header(header::COOKIE, cookie)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    fn logout_request(uri: &str, cookie: &str, csrf: &str) -> Request<Body> {
+        Request::post(uri)
+            .header(header::COOKIE, cookie)
+            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(Body::from(format!("csrf={}", csrf)))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn logs_out_one_browser_or_all_of_them() {
+        let server = TestServer::new().await;
+        let (laptop, laptop_csrf) = login(&server).await;
+        let (phone, phone_csrf) = login(&server).await;
+
+        let response = server
+            .send(logout_request("/_logout", &laptop, "wrong"))
+            .await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let response = server
+            .send(logout_request("/_logout", &laptop, &laptop_csrf))
+            .await;
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        assert!(response.headers()[header::SET_COOKIE]
+            .to_str()
+            .unwrap()
+            .contains("Max-Age=0"));
+        let response = server.send(session_request(&phone)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = server
+            .send(logout_request("/_logout/everywhere", &phone, &phone_csrf))
+            .await;
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        for cookie in [&laptop, &phone] {
+            let response = server.send(session_request(cookie)).await;
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        }
+    }
+}
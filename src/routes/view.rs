@@ -1,6 +1,10 @@
 use crate::{
     error::{AppError, Result},
-    models::post::Post,
+    markdown::RenderContext,
+    models::user::Scope,
+    storage::AttachmentBody,
+    utils::{auth::authenticate_read, diff::unified_diff},
+    AppState,
 };
 use axum::body::Body;
 use axum::http::{header, HeaderMap};
@@ -9,16 +13,23 @@ use axum::{
     extract::{Path, State},
     response::{Html, IntoResponse},
 };
-use std::path::PathBuf;
-use tokio::fs::File;
 use tokio_util::io::ReaderStream;
 
 pub async fn view_post(
     State(state): State<crate::AppState>,
     Path((username, id)): Path<(String, String)>,
 ) -> Result<Html<String>> {
-    let post = Post::load(&state.data_dir, &username, &id).await?;
-    let rendered_content = post.render_content();
+    let post = state.posts.load_document(&username, &id).await?;
+    let attachments = state.posts.attachment_names(&username, &id).await?;
+    let context = RenderContext::load(
+        state.posts.as_ref(),
+        &state.notes,
+        &username,
+        &post,
+        attachments,
+    )
+    .await?;
+    let rendered_content = post.render_content(&context);
     let html = state
         .templates
         .render(&state.data_dir, &username, &post, &rendered_content);
@@ -30,27 +41,132 @@ pub async fn serve_attachment(
     State(state): State<crate::AppState>,
     Path((username, id, filename)): Path<(String, String, String)>,
 ) -> Result<Response<Body>> {
-    // Construct the file path
-    let mut path = PathBuf::from(&state.data_dir);
-    path.push(&username);
-    path.push(&id);
-    path.push("attachments");
-    path.push(&filename);
-
-    let file = match File::open(&path).await {
-        Ok(file) => file,
-        Err(_) => return Err(AppError::NotFound),
+    let attachment = state.posts.attachment(&username, &id, &filename).await?;
+    Ok(attachment_response(attachment, &filename))
+}
+
+fn attachment_response(attachment: AttachmentBody, filename: &str) -> Response<Body> {
+    let file = match attachment {
+        AttachmentBody::Stream(file) => file,
+        AttachmentBody::Redirect(url) => return Redirect::temporary(&url).into_response(),
     };
 
     let stream = ReaderStream::new(file);
     let body = Body::from_stream(stream);
 
     let mut headers = HeaderMap::new();
-    if let Some(mime_type) = mime_guess::from_path(&path).first_raw() {
+    if let Some(mime_type) = mime_guess::from_path(filename).first_raw() {
         headers.insert(header::CONTENT_TYPE, mime_type.parse().unwrap());
     }
 
-    Ok((headers, body).into_response())
+    (headers, body).into_response()
+}
+
+/// Revisions can show what an author has since taken out of a post, so they
+/// are only shown to the author, and only while the post is still published
+async fn check_history_access(
+    headers: &HeaderMap,
+    state: &AppState,
+    username: &str,
+    id: &str,
+) -> Result<()> {
+    let viewer = authenticate_read(headers, state)
+        .await?
+        .require(Scope::Read)?;
+    if viewer != username {
+        return Err(AppError::NotFound);
+    }
+    state.posts.load_document(username, id).await?;
+    Ok(())
+}
+
+pub async fn view_revisions(
+    State(state): State<crate::AppState>,
+    headers: HeaderMap,
+    Path((username, id)): Path<(String, String)>,
+) -> Result<Html<String>> {
+    check_history_access(&headers, &state, &username, &id).await?;
+    let revisions = state.posts.revisions(&username, &id).await?;
+    if revisions.is_empty() {
+        return Err(AppError::NotFound);
+    }
+
+    let items: String = revisions
+        .iter()
+        .rev()
+        .map(|revision| {
+            format!(
+                "<li><a href=\"{}/\">Revision {}</a> - {}</li>",
+                revision.number,
+                revision.number,
+                handlebars::html_escape(&revision.name)
+            )
+        })
+        .collect();
+    Ok(Html(format!(
+        "<html><body><h1>Revisions of {}/{}</h1><ul>{}</ul></body></html>",
+        handlebars::html_escape(&username),
+        handlebars::html_escape(&id),
+        items
+    )))
+}
+
+pub async fn view_revision(
+    State(state): State<crate::AppState>,
+    headers: HeaderMap,
+    Path((username, id, number)): Path<(String, String, u64)>,
+) -> Result<Html<String>> {
+    check_history_access(&headers, &state, &username, &id).await?;
+    let revision = state.posts.revision(&username, &id, number).await?;
+    let post = revision.to_post(&id);
+    let attachments: Vec<String> = revision.attachments.keys().cloned().collect();
+    let context = RenderContext::load(
+        state.posts.as_ref(),
+        &state.notes,
+        &username,
+        &post,
+        attachments,
+    )
+    .await?;
+    let rendered_content = post.render_content(&context);
+    let html = state
+        .templates
+        .render(&state.data_dir, &username, &post, &rendered_content);
+
+    Ok(Html(html))
+}
+
+pub async fn serve_revision_attachment(
+    State(state): State<crate::AppState>,
+    headers: HeaderMap,
+    Path((username, id, number, filename)): Path<(String, String, u64, String)>,
+) -> Result<Response<Body>> {
+    check_history_access(&headers, &state, &username, &id).await?;
+    let revision = state.posts.revision(&username, &id, number).await?;
+    let hash = revision
+        .attachments
+        .get(&filename)
+        .ok_or(AppError::NotFound)?;
+
+    // An attachment the author has since removed stays removed
+    let current = state.posts.manifest(&username, &id).await?;
+    if !current.values().any(|h| h == hash) {
+        return Err(AppError::NotFound);
+    }
+    let content_type = mime_guess::from_path(&filename).first_or_octet_stream();
+    let attachment = state.blobs.get(hash, content_type.as_ref()).await?;
+    Ok(attachment_response(attachment, &filename))
+}
+
+pub async fn view_diff(
+    State(state): State<crate::AppState>,
+    headers: HeaderMap,
+    Path((username, id, from, to)): Path<(String, String, u64, u64)>,
+) -> Result<String> {
+    check_history_access(&headers, &state, &username, &id).await?;
+    let from = state.posts.revision(&username, &id, from).await?;
+    let to = state.posts.revision(&username, &id, to).await?;
+    Ok(unified_diff(&from, &to))
 }
 
 pub async fn redirect_to_github() -> Redirect {
@@ -61,9 +177,22 @@ pub async fn view_user_index(
     State(state): State<crate::AppState>,
     Path(username): Path<String>,
 ) -> Result<Html<String>> {
-    match Post::load(&state.data_dir, &username, "index").await {
+    match state.posts.load_document(&username, "index").await {
         Ok(post) => {
-            let rendered_content = post.render_content();
+            let attachments = state
+                .posts
+                .attachment_names(&username, "index")
+                .await
+                .unwrap_or_default();
+            let context = RenderContext::load(
+                state.posts.as_ref(),
+                &state.notes,
+                &username,
+                &post,
+                attachments,
+            )
+            .await?;
+            let rendered_content = post.render_content(&context);
             let html = state
                 .templates
                 .render(&state.data_dir, &username, &post, &rendered_content);
@@ -74,7 +203,10 @@ pub async fn view_user_index(
                 "{} does not have a landing page, if you are {} you can create one by making a post with the id index",
                 username, username
             );
-            Ok(Html(format!("<html><body><p>{}</p></body></html>", message)))
+            Ok(Html(format!(
+                "<html><body><p>{}</p></body></html>",
+                message
+            )))
         }
     }
 }
@@ -89,4 +221,14 @@ pub fn view_routes() -> axum::Router<crate::AppState> {
             "/:username/:id/attachments/:file",
             axum::routing::get(serve_attachment),
         )
+        .route("/:username/:id/rev/", axum::routing::get(view_revisions))
+        .route("/:username/:id/rev/:n/", axum::routing::get(view_revision))
+        .route(
+            "/:username/:id/rev/:n/attachments/:file",
+            axum::routing::get(serve_revision_attachment),
+        )
+        .route(
+            "/:username/:id/diff/:from/:to",
+            axum::routing::get(view_diff),
+        )
 }
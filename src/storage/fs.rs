@@ -0,0 +1,1044 @@
+use super::{
+    check_hash, check_id, hash_blob, read_attachments, store_attachments, AttachmentBody,
+    AttachmentChanges, AttachmentReader, BlobInfo, BlobStore, Manifest, PostStore, PostSummary,
+    Revision, RevisionSummary, TrashEntry, UserStore,
+};
+use crate::{
+    error::{AppError, Result},
+    models::{
+        metadata::Metadata,
+        post::{Post, PostMetadata},
+        user::User,
+    },
+};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    fs,
+    io::{AsyncReadExt, AsyncWriteExt},
+    sync::{Mutex as AsyncMutex, OwnedMutexGuard},
+};
+use uuid::Uuid;
+
+/// Stores each post as `<data_dir>/<username>/<id>/{metadata.json,content.md,attachments.json}`,
+/// with the attachment bytes themselves kept in a shared [`BlobStore`],
+/// revisions as `<data_dir>/.revisions/<username>/<id>/<n>.json` and
+/// unpublished posts moved to `<data_dir>/.trash/<username>/<id>/`
+pub struct FsPostStore {
+    data_dir: PathBuf,
+    blobs: Arc<dyn BlobStore>,
+    /// How many revisions to keep per post; `None` keeps them all
+    revision_limit: Option<usize>,
+    /// Held while a post is changed, by post directory, so two saves cannot
+    /// both claim the same revision number
+    locks: Mutex<HashMap<PathBuf, Arc<AsyncMutex<()>>>>,
+}
+
+impl FsPostStore {
+    pub fn new(
+        data_dir: PathBuf,
+        blobs: Arc<dyn BlobStore>,
+        revision_limit: Option<usize>,
+    ) -> Self {
+        Self {
+            data_dir,
+            blobs,
+            revision_limit,
+            locks: Mutex::new(HashMap::new()),
+        }
+    }
+
+    async fn lock_post(&self, post_dir: &Path) -> OwnedMutexGuard<()> {
+        let lock = {
+            let mut locks = self.locks.lock().unwrap();
+            // Forget locks nobody holds or waits on
+            locks.retain(|_, lock| Arc::strong_count(lock) > 1);
+            locks.entry(post_dir.to_path_buf()).or_default().clone()
+        };
+        lock.lock_owned().await
+    }
+
+    fn post_dir(&self, username: &str, id: &str) -> Result<PathBuf> {
+        check_id(username)?;
+        check_id(id)?;
+        Ok(self.data_dir.join(username).join(id))
+    }
+
+    fn trash_dir(&self, username: &str, id: &str) -> Result<PathBuf> {
+        check_id(username)?;
+        check_id(id)?;
+        Ok(self.data_dir.join(".trash").join(username).join(id))
+    }
+
+    fn revisions_dir(&self, username: &str, id: &str) -> Result<PathBuf> {
+        check_id(username)?;
+        check_id(id)?;
+        Ok(self.data_dir.join(".revisions").join(username).join(id))
+    }
+
+    async fn revision_numbers(&self, username: &str, id: &str) -> Result<Vec<u64>> {
+        let dir = self.revisions_dir(username, id)?;
+        if !dir.exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut numbers = Vec::new();
+        let mut entries = fs::read_dir(dir)
+            .await
+            .map_err(|e| AppError::Internal(e.to_string()))?;
+        while let Some(entry) = entries
+            .next_entry()
+            .await
+            .map_err(|e| AppError::Internal(e.to_string()))?
+        {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if let Some(number) = name.strip_suffix(".json").and_then(|n| n.parse().ok()) {
+                numbers.push(number);
+            }
+        }
+        numbers.sort_unstable();
+        Ok(numbers)
+    }
+
+    /// Writes `post` as the next revision, dropping the oldest ones beyond
+    /// the revision limit
+    async fn record_revision(
+        &self,
+        username: &str,
+        id: &str,
+        post: &Post,
+        manifest: &Manifest,
+    ) -> Result<()> {
+        let dir = self.revisions_dir(username, id)?;
+        fs::create_dir_all(&dir)
+            .await
+            .map_err(|e| AppError::Internal(e.to_string()))?;
+
+        let numbers = self.revision_numbers(username, id).await?;
+        let number = numbers.last().map_or(1, |n| n + 1);
+        let created = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let revision = Revision::new(number, created, post, manifest);
+
+        let tmp_path = dir.join(format!(".{}.tmp", Uuid::new_v4()));
+        fs::write(&tmp_path, serde_json::to_string_pretty(&revision).unwrap())
+            .await
+            .map_err(|e| AppError::Internal(e.to_string()))?;
+        fs::rename(&tmp_path, dir.join(format!("{}.json", number)))
+            .await
+            .map_err(|e| AppError::Internal(e.to_string()))?;
+
+        if let Some(limit) = self.revision_limit {
+            let total = numbers.len() + 1;
+            for old in numbers.iter().take(total.saturating_sub(limit)) {
+                fs::remove_file(dir.join(format!("{}.json", old)))
+                    .await
+                    .map_err(|e| AppError::Internal(e.to_string()))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Like [`PostStore::save`], but also returns whether the post was
+    /// already published, as decided while the post is locked
+    pub async fn save_post(
+        &self,
+        username: &str,
+        id: &str,
+        post: &Post,
+    ) -> Result<(AttachmentChanges, bool)> {
+        let post_dir = self.post_dir(username, id)?;
+        let _lock = self.lock_post(&post_dir).await;
+        let user_dir = self.data_dir.join(username);
+        fs::create_dir_all(&user_dir)
+            .await
+            .map_err(|e| AppError::Internal(e.to_string()))?;
+
+        let existed = post_dir.exists();
+        // Blobs are content-addressed, so storing them before the post is
+        // written leaves nothing behind that the garbage collector won't remove
+        let old_manifest = self.manifest(username, id).await?;
+        let manifest = store_attachments(self.blobs.as_ref(), post).await?;
+
+        let staging_dir = user_dir.join(format!(".{}.tmp-{}", id, Uuid::new_v4()));
+        if let Err(e) = write_post_files(&staging_dir, post, &manifest).await {
+            let _ = fs::remove_dir_all(&staging_dir).await;
+            return Err(e);
+        }
+        swap_into_place(&staging_dir, &post_dir, &user_dir, id).await?;
+        // The new version is already live, so failing now would only have
+        // the client retry a save that happened
+        if let Err(e) = self.record_revision(username, id, post, &manifest).await {
+            eprintln!("Failed to record a revision of {}/{}: {}", username, id, e);
+        }
+
+        Ok((
+            AttachmentChanges::between(&old_manifest, &manifest),
+            existed,
+        ))
+    }
+
+    /// Cleans up after saves interrupted by a crash: half-written staging
+    /// directories are removed and a post whose previous version was moved
+    /// aside but not yet replaced gets it back
+    pub async fn recover_interrupted_saves(&self) -> Result<()> {
+        for username in self.usernames().await? {
+            let user_dir = self.data_dir.join(&username);
+            let mut entries = fs::read_dir(&user_dir)
+                .await
+                .map_err(|e| AppError::Internal(e.to_string()))?;
+            while let Some(entry) = entries
+                .next_entry()
+                .await
+                .map_err(|e| AppError::Internal(e.to_string()))?
+            {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                let Some(rest) = name.strip_prefix('.') else {
+                    continue;
+                };
+
+                if rest.rsplit_once(".tmp-").is_some() {
+                    fs::remove_dir_all(entry.path())
+                        .await
+                        .map_err(|e| AppError::Internal(e.to_string()))?;
+                } else if let Some((id, _)) = rest.rsplit_once(".old-") {
+                    let post_dir = user_dir.join(id);
+                    if check_id(id).is_ok() && !post_dir.exists() {
+                        fs::rename(entry.path(), &post_dir)
+                            .await
+                            .map_err(|e| AppError::Internal(e.to_string()))?;
+                        eprintln!("Restored {}/{} after an interrupted save", username, id);
+                    } else {
+                        fs::remove_dir_all(entry.path())
+                            .await
+                            .map_err(|e| AppError::Internal(e.to_string()))?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The attachments a post still keeps in the per-post `attachments/`
+    /// directory used by older versions, by filename
+    pub async fn legacy_attachments(
+        &self,
+        username: &str,
+        id: &str,
+    ) -> Result<Vec<(String, Vec<u8>)>> {
+        let legacy_dir = self.post_dir(username, id)?.join("attachments");
+        if !legacy_dir.is_dir() {
+            return Ok(Vec::new());
+        }
+
+        let mut attachments = Vec::new();
+        let mut entries = fs::read_dir(&legacy_dir)
+            .await
+            .map_err(|e| AppError::Internal(e.to_string()))?;
+        while let Some(entry) = entries
+            .next_entry()
+            .await
+            .map_err(|e| AppError::Internal(e.to_string()))?
+        {
+            let data = fs::read(entry.path())
+                .await
+                .map_err(|e| AppError::Internal(e.to_string()))?;
+            attachments.push((entry.file_name().to_string_lossy().into_owned(), data));
+        }
+        Ok(attachments)
+    }
+
+    /// A trashed post, without its attachment contents
+    #[cfg_attr(not(feature = "sqlite"), allow(dead_code))]
+    pub async fn load_trashed(&self, username: &str, id: &str) -> Result<Post> {
+        read_post(&self.trash_dir(username, id)?, id).await
+    }
+
+    /// Moves attachments out of the per-post `attachments/` directories used
+    /// by older versions and into the blob store
+    pub async fn migrate_legacy_attachments(&self) -> Result<()> {
+        for username in self.usernames().await? {
+            for post in self.list(&username).await? {
+                let post_dir = self.post_dir(&username, &post.id)?;
+                let legacy_dir = post_dir.join("attachments");
+                if !legacy_dir.is_dir() {
+                    continue;
+                }
+
+                let mut manifest = self.manifest(&username, &post.id).await?;
+                for (filename, data) in self.legacy_attachments(&username, &post.id).await? {
+                    let hash = hash_blob(&data);
+                    if !self.blobs.touch(&hash).await? {
+                        self.blobs.put(&hash, data).await?;
+                    }
+                    manifest.entry(filename).or_insert(hash);
+                }
+
+                write_manifest(&post_dir, &manifest).await?;
+                fs::remove_dir_all(&legacy_dir)
+                    .await
+                    .map_err(|e| AppError::Internal(e.to_string()))?;
+                eprintln!("Migrated attachments of {}/{}", username, post.id);
+            }
+        }
+        Ok(())
+    }
+}
+
+async fn read_metadata(post_dir: &Path) -> Result<PostMetadata> {
+    let metadata_str = fs::read_to_string(post_dir.join("metadata.json"))
+        .await
+        .map_err(|e| AppError::Internal(e.to_string()))?;
+    serde_json::from_str(&metadata_str).map_err(|e| AppError::Internal(e.to_string()))
+}
+
+/// Reads the post kept in `post_dir`, without its attachments
+async fn read_post(post_dir: &Path, id: &str) -> Result<Post> {
+    let content_path = post_dir.join("content.md");
+    if !post_dir.join("metadata.json").exists() || !content_path.exists() {
+        return Err(AppError::NotFound);
+    }
+
+    let post_metadata = read_metadata(post_dir).await?;
+    let content = fs::read_to_string(content_path)
+        .await
+        .map_err(|e| AppError::Internal(e.to_string()))?;
+
+    Ok(Post {
+        name: post_metadata.name,
+        path: post_metadata.path,
+        metadata: Metadata {
+            id: Some(id.to_string()),
+            extra: post_metadata.extra,
+        },
+        content,
+        attachments: None,
+        attachment_hashes: None,
+    })
+}
+
+/// Writes every file of a post into a fresh `dir`
+async fn write_post_files(dir: &Path, post: &Post, manifest: &Manifest) -> Result<()> {
+    fs::create_dir(dir)
+        .await
+        .map_err(|e| AppError::Internal(e.to_string()))?;
+
+    let metadata = PostMetadata {
+        name: post.name.clone(),
+        path: post.path.clone(),
+        extra: post.metadata.extra.clone(),
+    };
+    fs::write(
+        dir.join("metadata.json"),
+        serde_json::to_string_pretty(&metadata).unwrap(),
+    )
+    .await
+    .map_err(|e| AppError::Internal(e.to_string()))?;
+
+    fs::write(dir.join("content.md"), &post.content)
+        .await
+        .map_err(|e| AppError::Internal(e.to_string()))?;
+
+    write_manifest(dir, manifest).await
+}
+
+/// Atomically exchanges two existing paths
+#[cfg(target_os = "linux")]
+fn exchange(a: &Path, b: &Path) -> std::io::Result<()> {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+    let a = CString::new(a.as_os_str().as_bytes())?;
+    let b = CString::new(b.as_os_str().as_bytes())?;
+    // SAFETY: both paths are NUL-terminated and outlive the call
+    let result = unsafe {
+        libc::renameat2(
+            libc::AT_FDCWD,
+            a.as_ptr(),
+            libc::AT_FDCWD,
+            b.as_ptr(),
+            libc::RENAME_EXCHANGE,
+        )
+    };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(std::io::Error::last_os_error())
+    }
+}
+
+/// Replaces `post_dir` with `staging_dir`. On Linux the two are exchanged
+/// atomically, so readers always see one version or the other. Elsewhere,
+/// and on filesystems that cannot exchange, the previous version is moved
+/// aside first, so a crash in between leaves it to be restored by
+/// [`FsPostStore::recover_interrupted_saves`].
+async fn swap_into_place(
+    staging_dir: &Path,
+    post_dir: &Path,
+    user_dir: &Path,
+    id: &str,
+) -> Result<()> {
+    #[cfg(target_os = "linux")]
+    if post_dir.exists() {
+        let (from, to) = (staging_dir.to_path_buf(), post_dir.to_path_buf());
+        let exchanged = tokio::task::spawn_blocking(move || exchange(&from, &to))
+            .await
+            .map_err(|e| AppError::Internal(e.to_string()))?;
+        match exchanged {
+            // The staging directory now holds the previous version
+            Ok(()) => {
+                return fs::remove_dir_all(staging_dir)
+                    .await
+                    .map_err(|e| AppError::Internal(e.to_string()))
+            }
+            Err(e) if e.raw_os_error() == Some(libc::EINVAL) => {}
+            Err(e) => {
+                let _ = fs::remove_dir_all(staging_dir).await;
+                return Err(AppError::Internal(e.to_string()));
+            }
+        }
+    }
+
+    let old_dir = user_dir.join(format!(".{}.old-{}", id, Uuid::new_v4()));
+    let had_old = post_dir.exists();
+    if had_old {
+        fs::rename(post_dir, &old_dir)
+            .await
+            .map_err(|e| AppError::Internal(e.to_string()))?;
+    }
+
+    if let Err(e) = fs::rename(staging_dir, post_dir).await {
+        if had_old {
+            let _ = fs::rename(&old_dir, post_dir).await;
+        }
+        let _ = fs::remove_dir_all(staging_dir).await;
+        return Err(AppError::Internal(e.to_string()));
+    }
+
+    if had_old {
+        fs::remove_dir_all(&old_dir)
+            .await
+            .map_err(|e| AppError::Internal(e.to_string()))?;
+    }
+    Ok(())
+}
+
+async fn write_manifest(post_dir: &Path, manifest: &Manifest) -> Result<()> {
+    fs::write(
+        post_dir.join("attachments.json"),
+        serde_json::to_string_pretty(manifest).unwrap(),
+    )
+    .await
+    .map_err(|e| AppError::Internal(e.to_string()))
+}
+
+#[async_trait]
+impl PostStore for FsPostStore {
+    async fn save(&self, username: &str, id: &str, post: &Post) -> Result<AttachmentChanges> {
+        Ok(self.save_post(username, id, post).await?.0)
+    }
+
+    async fn load(&self, username: &str, id: &str) -> Result<Post> {
+        let mut post = self.load_document(username, id).await?;
+        let manifest = self.manifest(username, id).await?;
+        post.attachments = read_attachments(self.blobs.as_ref(), &manifest).await?;
+        Ok(post)
+    }
+
+    async fn load_document(&self, username: &str, id: &str) -> Result<Post> {
+        read_post(&self.post_dir(username, id)?, id).await
+    }
+
+    async fn manifest(&self, username: &str, id: &str) -> Result<Manifest> {
+        let manifest_path = self.post_dir(username, id)?.join("attachments.json");
+        if !manifest_path.exists() {
+            return Ok(Manifest::new());
+        }
+
+        let manifest_str = fs::read_to_string(manifest_path)
+            .await
+            .map_err(|e| AppError::Internal(e.to_string()))?;
+        serde_json::from_str(&manifest_str).map_err(|e| AppError::Internal(e.to_string()))
+    }
+
+    async fn delete(&self, username: &str, id: &str) -> Result<()> {
+        let post_dir = self.post_dir(username, id)?;
+        let _lock = self.lock_post(&post_dir).await;
+        if !post_dir.exists() {
+            return Err(AppError::NotFound);
+        }
+
+        // A post unpublished again replaces its older copy in the trash
+        let trash_dir = self.trash_dir(username, id)?;
+        if trash_dir.exists() {
+            fs::remove_dir_all(&trash_dir)
+                .await
+                .map_err(|e| AppError::Internal(e.to_string()))?;
+        }
+        fs::create_dir_all(trash_dir.parent().unwrap_or(&self.data_dir))
+            .await
+            .map_err(|e| AppError::Internal(e.to_string()))?;
+        fs::rename(&post_dir, &trash_dir)
+            .await
+            .map_err(|e| AppError::Internal(e.to_string()))?;
+
+        let deleted = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        fs::write(trash_dir.join("deleted"), deleted.to_string())
+            .await
+            .map_err(|e| AppError::Internal(e.to_string()))
+    }
+
+    async fn list(&self, username: &str) -> Result<Vec<PostSummary>> {
+        check_id(username)?;
+        let user_dir = self.data_dir.join(username);
+        if !user_dir.exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut posts = Vec::new();
+        let mut entries = fs::read_dir(user_dir)
+            .await
+            .map_err(|e| AppError::Internal(e.to_string()))?;
+        while let Some(entry) = entries
+            .next_entry()
+            .await
+            .map_err(|e| AppError::Internal(e.to_string()))?
+        {
+            let id = entry.file_name().to_string_lossy().into_owned();
+            if check_id(&id).is_err() || !entry.path().join("metadata.json").exists() {
+                continue;
+            }
+
+            let post_metadata = read_metadata(&entry.path()).await?;
+            let modified = fs::metadata(entry.path().join("content.md"))
+                .await
+                .and_then(|m| m.modified())
+                .unwrap_or(SystemTime::UNIX_EPOCH);
+
+            posts.push(PostSummary {
+                id,
+                name: post_metadata.name,
+                path: post_metadata.path,
+                modified: modified
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .unwrap_or(0),
+            });
+        }
+
+        posts.sort_by(|a, b| a.id.cmp(&b.id));
+        Ok(posts)
+    }
+
+    async fn usernames(&self) -> Result<Vec<String>> {
+        let mut usernames = Vec::new();
+        let mut entries = fs::read_dir(&self.data_dir)
+            .await
+            .map_err(|e| AppError::Internal(e.to_string()))?;
+        while let Some(entry) = entries
+            .next_entry()
+            .await
+            .map_err(|e| AppError::Internal(e.to_string()))?
+        {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if check_id(&name).is_ok() && entry.path().is_dir() {
+                usernames.push(name);
+            }
+        }
+        Ok(usernames)
+    }
+
+    async fn attachment(&self, username: &str, id: &str, filename: &str) -> Result<AttachmentBody> {
+        let manifest = self.manifest(username, id).await?;
+        let hash = manifest.get(filename).ok_or(AppError::NotFound)?;
+        let content_type = mime_guess::from_path(filename).first_or_octet_stream();
+        self.blobs.get(hash, content_type.as_ref()).await
+    }
+
+    async fn revisions(&self, username: &str, id: &str) -> Result<Vec<RevisionSummary>> {
+        let mut revisions = Vec::new();
+        for number in self.revision_numbers(username, id).await? {
+            revisions.push(self.revision(username, id, number).await?.summary());
+        }
+        Ok(revisions)
+    }
+
+    async fn revision(&self, username: &str, id: &str, number: u64) -> Result<Revision> {
+        let path = self
+            .revisions_dir(username, id)?
+            .join(format!("{}.json", number));
+        if !path.exists() {
+            return Err(AppError::NotFound);
+        }
+
+        let revision_str = fs::read_to_string(path)
+            .await
+            .map_err(|e| AppError::Internal(e.to_string()))?;
+        serde_json::from_str(&revision_str).map_err(|e| AppError::Internal(e.to_string()))
+    }
+
+    async fn trash(&self, username: &str) -> Result<Vec<TrashEntry>> {
+        check_id(username)?;
+        let user_trash = self.data_dir.join(".trash").join(username);
+        if !user_trash.exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut trash = Vec::new();
+        let mut entries = fs::read_dir(user_trash)
+            .await
+            .map_err(|e| AppError::Internal(e.to_string()))?;
+        while let Some(entry) = entries
+            .next_entry()
+            .await
+            .map_err(|e| AppError::Internal(e.to_string()))?
+        {
+            let id = entry.file_name().to_string_lossy().into_owned();
+            if check_id(&id).is_err() || !entry.path().join("metadata.json").exists() {
+                continue;
+            }
+
+            let post_metadata = read_metadata(&entry.path()).await?;
+            let deleted = fs::read_to_string(entry.path().join("deleted"))
+                .await
+                .ok()
+                .and_then(|d| d.trim().parse().ok())
+                .unwrap_or(0);
+            let attachments = match fs::read_to_string(entry.path().join("attachments.json")).await
+            {
+                Ok(manifest) => serde_json::from_str(&manifest)
+                    .map_err(|e| AppError::Internal(e.to_string()))?,
+                Err(_) => Manifest::new(),
+            };
+
+            trash.push(TrashEntry {
+                id,
+                name: post_metadata.name,
+                path: post_metadata.path,
+                deleted,
+                attachments,
+            });
+        }
+
+        trash.sort_by(|a, b| a.id.cmp(&b.id));
+        Ok(trash)
+    }
+
+    async fn trash_usernames(&self) -> Result<Vec<String>> {
+        let trash_dir = self.data_dir.join(".trash");
+        if !trash_dir.exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut usernames = Vec::new();
+        let mut entries = fs::read_dir(trash_dir)
+            .await
+            .map_err(|e| AppError::Internal(e.to_string()))?;
+        while let Some(entry) = entries
+            .next_entry()
+            .await
+            .map_err(|e| AppError::Internal(e.to_string()))?
+        {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if check_id(&name).is_ok() && entry.path().is_dir() {
+                usernames.push(name);
+            }
+        }
+        Ok(usernames)
+    }
+
+    async fn restore_trashed(&self, username: &str, id: &str) -> Result<()> {
+        let trash_dir = self.trash_dir(username, id)?;
+        if !trash_dir.exists() {
+            return Err(AppError::NotFound);
+        }
+        let post_dir = self.post_dir(username, id)?;
+        let _lock = self.lock_post(&post_dir).await;
+        if post_dir.exists() {
+            return Err(AppError::Conflict(format!(
+                "A post with the id {} is already published",
+                id
+            )));
+        }
+
+        let _ = fs::remove_file(trash_dir.join("deleted")).await;
+        fs::create_dir_all(self.data_dir.join(username))
+            .await
+            .map_err(|e| AppError::Internal(e.to_string()))?;
+        fs::rename(&trash_dir, &post_dir)
+            .await
+            .map_err(|e| AppError::Internal(e.to_string()))
+    }
+
+    async fn purge_trashed(&self, username: &str, id: &str) -> Result<()> {
+        let post_dir = self.post_dir(username, id)?;
+        let _lock = self.lock_post(&post_dir).await;
+        let trash_dir = self.trash_dir(username, id)?;
+        if !trash_dir.exists() {
+            return Err(AppError::NotFound);
+        }
+        fs::remove_dir_all(&trash_dir)
+            .await
+            .map_err(|e| AppError::Internal(e.to_string()))?;
+
+        let revisions_dir = self.revisions_dir(username, id)?;
+        if !post_dir.exists() && revisions_dir.exists() {
+            fs::remove_dir_all(revisions_dir)
+                .await
+                .map_err(|e| AppError::Internal(e.to_string()))?;
+        }
+        Ok(())
+    }
+}
+
+/// Stores blobs as `<data_dir>/.blobs/<first two hex chars>/<hash>`
+pub struct FsBlobStore {
+    blobs_dir: PathBuf,
+}
+
+impl FsBlobStore {
+    pub fn new(data_dir: &Path) -> Self {
+        Self {
+            blobs_dir: data_dir.join(".blobs"),
+        }
+    }
+
+    fn blob_path(&self, hash: &str) -> Result<PathBuf> {
+        check_hash(hash)?;
+        Ok(self.blobs_dir.join(&hash[..2]).join(hash))
+    }
+}
+
+#[async_trait]
+impl BlobStore for FsBlobStore {
+    async fn put(&self, hash: &str, data: Vec<u8>) -> Result<()> {
+        let path = self.blob_path(hash)?;
+        let shard_dir = path.parent().unwrap();
+        fs::create_dir_all(shard_dir)
+            .await
+            .map_err(|e| AppError::Internal(e.to_string()))?;
+
+        // Write under a temporary name so readers never see a partial blob
+        let tmp_path = shard_dir.join(format!(".{}.tmp", Uuid::new_v4()));
+        fs::write(&tmp_path, data)
+            .await
+            .map_err(|e| AppError::Internal(e.to_string()))?;
+        fs::rename(&tmp_path, &path)
+            .await
+            .map_err(|e| AppError::Internal(e.to_string()))
+    }
+
+    async fn put_stream(&self, hash: &str, mut reader: AttachmentReader) -> Result<()> {
+        let path = self.blob_path(hash)?;
+        let shard_dir = path.parent().unwrap();
+        fs::create_dir_all(shard_dir)
+            .await
+            .map_err(|e| AppError::Internal(e.to_string()))?;
+
+        // Hash while copying to a temporary file, only keeping it if it matches
+        let tmp_path = shard_dir.join(format!(".{}.tmp", Uuid::new_v4()));
+        let copied = async {
+            let mut file = fs::File::create(&tmp_path).await?;
+            let mut hasher = Sha256::new();
+            let mut buf = vec![0; 64 * 1024];
+            loop {
+                let n = reader.read(&mut buf).await?;
+                if n == 0 {
+                    break;
+                }
+                hasher.update(&buf[..n]);
+                file.write_all(&buf[..n]).await?;
+            }
+            file.flush().await?;
+            Ok::<_, std::io::Error>(hex::encode(hasher.finalize()))
+        }
+        .await;
+
+        match copied {
+            Ok(actual) if actual == hash => fs::rename(&tmp_path, &path)
+                .await
+                .map_err(|e| AppError::Internal(e.to_string())),
+            Ok(_) => {
+                let _ = fs::remove_file(&tmp_path).await;
+                let _ = fs::remove_dir(shard_dir).await;
+                Err(AppError::HashMismatch)
+            }
+            Err(e) => {
+                let _ = fs::remove_file(&tmp_path).await;
+                let _ = fs::remove_dir(shard_dir).await;
+                Err(AppError::Internal(e.to_string()))
+            }
+        }
+    }
+
+    async fn exists(&self, hash: &str) -> Result<bool> {
+        fs::try_exists(self.blob_path(hash)?)
+            .await
+            .map_err(|e| AppError::Internal(e.to_string()))
+    }
+
+    async fn touch(&self, hash: &str) -> Result<bool> {
+        let path = self.blob_path(hash)?;
+        let touched = tokio::task::spawn_blocking(move || {
+            std::fs::File::options()
+                .write(true)
+                .open(path)?
+                .set_modified(SystemTime::now())
+        })
+        .await
+        .map_err(|e| AppError::Internal(e.to_string()))?;
+
+        match touched {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(AppError::Internal(e.to_string())),
+        }
+    }
+
+    async fn get(&self, hash: &str, _content_type: &str) -> Result<AttachmentBody> {
+        match fs::File::open(self.blob_path(hash)?).await {
+            Ok(file) => Ok(AttachmentBody::Stream(Box::new(file))),
+            Err(_) => Err(AppError::NotFound),
+        }
+    }
+
+    async fn read(&self, hash: &str) -> Result<Vec<u8>> {
+        fs::read(self.blob_path(hash)?)
+            .await
+            .map_err(|_| AppError::NotFound)
+    }
+
+    async fn delete(&self, hash: &str) -> Result<()> {
+        let path = self.blob_path(hash)?;
+        fs::remove_file(&path)
+            .await
+            .map_err(|e| AppError::Internal(e.to_string()))?;
+
+        // Drop the shard directory once it is empty; fails harmlessly otherwise
+        if let Some(shard) = path.parent() {
+            let _ = fs::remove_dir(shard).await;
+        }
+        Ok(())
+    }
+
+    async fn list(&self) -> Result<Vec<BlobInfo>> {
+        let mut blobs = Vec::new();
+        if !self.blobs_dir.exists() {
+            return Ok(blobs);
+        }
+
+        let mut shards = fs::read_dir(&self.blobs_dir)
+            .await
+            .map_err(|e| AppError::Internal(e.to_string()))?;
+        while let Some(shard) = shards
+            .next_entry()
+            .await
+            .map_err(|e| AppError::Internal(e.to_string()))?
+        {
+            let mut entries = fs::read_dir(shard.path())
+                .await
+                .map_err(|e| AppError::Internal(e.to_string()))?;
+            while let Some(entry) = entries
+                .next_entry()
+                .await
+                .map_err(|e| AppError::Internal(e.to_string()))?
+            {
+                let hash = entry.file_name().to_string_lossy().into_owned();
+                if check_hash(&hash).is_err() {
+                    continue;
+                }
+
+                let modified = entry
+                    .metadata()
+                    .await
+                    .and_then(|m| m.modified())
+                    .unwrap_or(SystemTime::UNIX_EPOCH);
+                blobs.push(BlobInfo {
+                    hash,
+                    modified: modified
+                        .duration_since(UNIX_EPOCH)
+                        .map(|d| d.as_secs())
+                        .unwrap_or(0),
+                });
+            }
+        }
+        Ok(blobs)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct UsersFile {
+    users: HashMap<String, User>,
+}
+
+/// Stores users in `<data_dir>/users.json`
+pub struct FsUserStore {
+    path: PathBuf,
+}
+
+impl FsUserStore {
+    pub fn new(data_dir: &Path) -> Self {
+        Self {
+            path: data_dir.join("users.json"),
+        }
+    }
+}
+
+#[async_trait]
+impl UserStore for FsUserStore {
+    async fn load(&self) -> Result<Option<HashMap<String, User>>> {
+        if !self.path.exists() {
+            return Ok(None);
+        }
+
+        let content = fs::read_to_string(&self.path)
+            .await
+            .map_err(|e| AppError::Internal(e.to_string()))?;
+        let file: UsersFile =
+            serde_json::from_str(&content).map_err(|e| AppError::Internal(e.to_string()))?;
+        Ok(Some(file.users))
+    }
+
+    async fn save(&self, users: &HashMap<String, User>) -> Result<()> {
+        let file = UsersFile {
+            users: users.clone(),
+        };
+
+        // Written to a temporary file first so the watcher never reads a
+        // half-written file
+        let tmp = self.path.with_extension("json.tmp");
+        fs::write(&tmp, serde_json::to_string_pretty(&file).unwrap())
+            .await
+            .map_err(|e| AppError::Internal(e.to_string()))?;
+        fs::rename(&tmp, &self.path)
+            .await
+            .map_err(|e| AppError::Internal(e.to_string()))?;
+        Ok(())
+    }
+
+    fn watch_path(&self) -> Option<PathBuf> {
+        Some(self.path.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::metadata::Metadata;
+
+    fn post(content: &str) -> Post {
+        Post {
+            name: "Note".to_string(),
+            path: "note.md".to_string(),
+            metadata: Metadata {
+                id: Some("note".to_string()),
+                extra: HashMap::new(),
+            },
+            content: content.to_string(),
+            attachments: None,
+            attachment_hashes: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn save_replaces_previous_version() {
+        let dir = std::env::temp_dir().join(format!("dollpublish-fs-{}", Uuid::new_v4()));
+        let blobs = Arc::new(FsBlobStore::new(&dir));
+        let posts = FsPostStore::new(dir.clone(), blobs, None);
+
+        posts.save("alice", "note", &post("first")).await.unwrap();
+        posts.save("alice", "note", &post("second")).await.unwrap();
+
+        let saved = posts.load_document("alice", "note").await.unwrap();
+        assert_eq!(saved.content, "second");
+        let leftovers: Vec<_> = std::fs::read_dir(dir.join("alice"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .filter(|name| name != "note")
+            .collect();
+        assert!(leftovers.is_empty(), "left behind {:?}", leftovers);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_saves_get_distinct_revisions() {
+        let dir = std::env::temp_dir().join(format!("dollpublish-fs-{}", Uuid::new_v4()));
+        let blobs = Arc::new(FsBlobStore::new(&dir));
+        let posts = Arc::new(FsPostStore::new(dir.clone(), blobs, None));
+
+        let saves: Vec<_> = (0..16)
+            .map(|i| {
+                let posts = posts.clone();
+                tokio::spawn(async move {
+                    posts
+                        .save("alice", "note", &post(&format!("version {}", i)))
+                        .await
+                })
+            })
+            .collect();
+        for save in saves {
+            save.await.unwrap().unwrap();
+        }
+
+        let numbers: Vec<u64> = posts
+            .revisions("alice", "note")
+            .await
+            .unwrap()
+            .iter()
+            .map(|revision| revision.number)
+            .collect();
+        assert_eq!(numbers, (1..=16).collect::<Vec<_>>());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn restores_and_purges_trashed_posts() {
+        let dir = std::env::temp_dir().join(format!("dollpublish-fs-{}", Uuid::new_v4()));
+        let blobs = Arc::new(FsBlobStore::new(&dir));
+        let posts = FsPostStore::new(dir.clone(), blobs, None);
+
+        posts.save("alice", "note", &post("first")).await.unwrap();
+        posts.delete("alice", "note").await.unwrap();
+        assert!(posts.list("alice").await.unwrap().is_empty());
+        assert_eq!(posts.trash("alice").await.unwrap()[0].id, "note");
+
+        posts.restore_trashed("alice", "note").await.unwrap();
+        assert_eq!(
+            posts.load_document("alice", "note").await.unwrap().content,
+            "first"
+        );
+        assert!(posts.trash("alice").await.unwrap().is_empty());
+
+        // A post published again under the same id is not overwritten, and
+        // keeps the revisions when its trashed copy is purged
+        posts.delete("alice", "note").await.unwrap();
+        posts.save("alice", "note", &post("second")).await.unwrap();
+        assert!(matches!(
+            posts.restore_trashed("alice", "note").await,
+            Err(AppError::Conflict(_))
+        ));
+        posts.purge_trashed("alice", "note").await.unwrap();
+        assert!(posts.trash("alice").await.unwrap().is_empty());
+        assert_eq!(posts.revisions("alice", "note").await.unwrap().len(), 2);
+
+        posts.delete("alice", "note").await.unwrap();
+        posts.purge_trashed("alice", "note").await.unwrap();
+        assert!(posts.revisions("alice", "note").await.unwrap().is_empty());
+        assert!(matches!(
+            posts.restore_trashed("alice", "note").await,
+            Err(AppError::NotFound)
+        ));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,341 @@
+use super::{
+    check_id, fs::FsPostStore, AttachmentBody, AttachmentChanges, BlobStore, Manifest, PostStore,
+    PostSummary, Revision, RevisionSummary, TrashEntry,
+};
+use crate::{
+    error::{AppError, Result},
+    models::post::Post,
+};
+use async_trait::async_trait;
+use git2::{IndexAddOption, IndexEntry, IndexTime, Oid, Repository, Signature};
+use std::{
+    collections::{BTreeSet, HashMap},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::sync::Mutex;
+
+/// Where each repository keeps the attachment blobs its posts reference, by
+/// SHA-256. They are only written to the repository, not the user directory.
+const BLOBS_DIR: &str = ".blobs";
+
+/// Wraps an [`FsPostStore`] so that every change to a user's directory is
+/// committed to a git repository in that directory, along with the
+/// attachments its posts reference
+pub struct GitPostStore {
+    inner: FsPostStore,
+    blobs: Arc<dyn BlobStore>,
+    data_dir: PathBuf,
+    /// Serializes commits, which would otherwise race on the index lock
+    lock: Mutex<()>,
+}
+
+fn internal(e: impl ToString) -> AppError {
+    AppError::Internal(e.to_string())
+}
+
+fn open_repo(user_dir: &Path) -> Result<Repository> {
+    match Repository::open(user_dir) {
+        Ok(repo) => Ok(repo),
+        Err(_) => Repository::init(user_dir).map_err(internal),
+    }
+}
+
+/// The attachment blobs in the last commit, by SHA-256
+fn committed_blobs(user_dir: &Path) -> Result<HashMap<String, Oid>> {
+    let repo = open_repo(user_dir)?;
+    let mut blobs = HashMap::new();
+    let Some(tree) = repo.head().ok().and_then(|head| head.peel_to_tree().ok()) else {
+        return Ok(blobs);
+    };
+    let Ok(entry) = tree.get_path(Path::new(BLOBS_DIR)) else {
+        return Ok(blobs);
+    };
+
+    for blob in repo.find_tree(entry.id()).map_err(internal)?.iter() {
+        if let Some(hash) = blob.name() {
+            blobs.insert(hash.to_string(), blob.id());
+        }
+    }
+    Ok(blobs)
+}
+
+/// Stages everything in `user_dir` except dot-files (such as in-progress
+/// saves), replaces the staged attachment blobs with `blobs` and commits it
+/// as `username`. Unless `always` is set, nothing is committed when nothing
+/// changed.
+fn commit_all(
+    user_dir: &Path,
+    username: &str,
+    message: &str,
+    blobs: &[(String, Oid)],
+    always: bool,
+) -> Result<()> {
+    let repo = open_repo(user_dir)?;
+
+    let mut index = repo.index().map_err(internal)?;
+    let mut skip_hidden = |path: &Path, _: &[u8]| -> i32 {
+        let hidden = path
+            .components()
+            .next()
+            .is_some_and(|c| c.as_os_str().to_string_lossy().starts_with('.'));
+        i32::from(hidden)
+    };
+    index
+        .add_all(["*"], IndexAddOption::DEFAULT, Some(&mut skip_hidden))
+        .map_err(internal)?;
+    index.update_all(["*"], None).map_err(internal)?;
+
+    // Blobs are already in the object database rather than the user
+    // directory, so they are staged by id
+    index
+        .remove_dir(Path::new(BLOBS_DIR), 0)
+        .map_err(internal)?;
+    for (hash, id) in blobs {
+        index
+            .add(&IndexEntry {
+                ctime: IndexTime::new(0, 0),
+                mtime: IndexTime::new(0, 0),
+                dev: 0,
+                ino: 0,
+                mode: 0o100644,
+                uid: 0,
+                gid: 0,
+                file_size: 0,
+                id: *id,
+                flags: 0,
+                flags_extended: 0,
+                path: format!("{}/{}", BLOBS_DIR, hash).into_bytes(),
+            })
+            .map_err(internal)?;
+    }
+    index.write().map_err(internal)?;
+
+    let tree_id = index.write_tree().map_err(internal)?;
+    let parent = repo.head().ok().and_then(|head| head.peel_to_commit().ok());
+    if !always && parent.as_ref().is_some_and(|p| p.tree_id() == tree_id) {
+        return Ok(());
+    }
+
+    let tree = repo.find_tree(tree_id).map_err(internal)?;
+    let signature =
+        Signature::now(username, &format!("{}@dollpublish", username)).map_err(internal)?;
+    let parents: Vec<_> = parent.iter().collect();
+    repo.commit(
+        Some("HEAD"),
+        &signature,
+        &signature,
+        message,
+        &tree,
+        &parents,
+    )
+    .map_err(internal)?;
+    Ok(())
+}
+
+impl GitPostStore {
+    pub fn new(inner: FsPostStore, blobs: Arc<dyn BlobStore>, data_dir: PathBuf) -> Self {
+        Self {
+            inner,
+            blobs,
+            data_dir,
+            lock: Mutex::new(()),
+        }
+    }
+
+    async fn commit(&self, username: &str, message: String) -> Result<()> {
+        self.commit_with(username, message, false).await
+    }
+
+    /// Commits the user directory even if nothing in it changed, to record
+    /// something that happened outside it
+    async fn commit_always(&self, username: &str, message: String) -> Result<()> {
+        self.commit_with(username, message, true).await
+    }
+
+    async fn commit_with(&self, username: &str, message: String, always: bool) -> Result<()> {
+        check_id(username)?;
+        let user_dir = self.data_dir.join(username);
+        let _guard = self.lock.lock().await;
+
+        let mut hashes = BTreeSet::new();
+        for post in self.inner.list(username).await? {
+            hashes.extend(self.inner.manifest(username, &post.id).await?.into_values());
+        }
+
+        // Only blobs new to the repository need reading from the blob store
+        let dir = user_dir.clone();
+        let mut committed = tokio::task::spawn_blocking(move || committed_blobs(&dir))
+            .await
+            .map_err(internal)??;
+        let mut blobs = Vec::new();
+        for hash in hashes {
+            let id = match committed.remove(&hash) {
+                Some(id) => id,
+                None => {
+                    let data = self.blobs.read(&hash).await?;
+                    let dir = user_dir.clone();
+                    tokio::task::spawn_blocking(move || {
+                        open_repo(&dir)?.blob(&data).map_err(internal)
+                    })
+                    .await
+                    .map_err(internal)??
+                }
+            };
+            blobs.push((hash, id));
+        }
+
+        let username = username.to_string();
+        tokio::task::spawn_blocking(move || {
+            commit_all(&user_dir, &username, &message, &blobs, always)
+        })
+        .await
+        .map_err(internal)?
+    }
+
+    /// Commits anything already in the user directories, such as posts
+    /// published before git storage was enabled
+    pub async fn commit_existing(&self) -> Result<()> {
+        for username in self.inner.usernames().await? {
+            self.commit(&username, "Import existing posts".to_string())
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl PostStore for GitPostStore {
+    async fn save(&self, username: &str, id: &str, post: &Post) -> Result<AttachmentChanges> {
+        let (changes, existed) = self.inner.save_post(username, id, post).await?;
+        let action = if existed { "republish" } else { "publish" };
+        self.commit(username, format!("{} {}", action, id)).await?;
+        Ok(changes)
+    }
+
+    async fn load(&self, username: &str, id: &str) -> Result<Post> {
+        self.inner.load(username, id).await
+    }
+
+    async fn load_document(&self, username: &str, id: &str) -> Result<Post> {
+        self.inner.load_document(username, id).await
+    }
+
+    async fn manifest(&self, username: &str, id: &str) -> Result<Manifest> {
+        self.inner.manifest(username, id).await
+    }
+
+    async fn delete(&self, username: &str, id: &str) -> Result<()> {
+        self.inner.delete(username, id).await?;
+        self.commit(username, format!("unpublish {}", id)).await
+    }
+
+    async fn list(&self, username: &str) -> Result<Vec<PostSummary>> {
+        self.inner.list(username).await
+    }
+
+    async fn usernames(&self) -> Result<Vec<String>> {
+        self.inner.usernames().await
+    }
+
+    async fn attachment(&self, username: &str, id: &str, filename: &str) -> Result<AttachmentBody> {
+        self.inner.attachment(username, id, filename).await
+    }
+
+    async fn revisions(&self, username: &str, id: &str) -> Result<Vec<RevisionSummary>> {
+        self.inner.revisions(username, id).await
+    }
+
+    async fn revision(&self, username: &str, id: &str, number: u64) -> Result<Revision> {
+        self.inner.revision(username, id, number).await
+    }
+
+    async fn trash(&self, username: &str) -> Result<Vec<TrashEntry>> {
+        self.inner.trash(username).await
+    }
+
+    async fn trash_usernames(&self) -> Result<Vec<String>> {
+        self.inner.trash_usernames().await
+    }
+
+    async fn restore_trashed(&self, username: &str, id: &str) -> Result<()> {
+        self.inner.restore_trashed(username, id).await?;
+        self.commit(username, format!("restore {}", id)).await
+    }
+
+    async fn purge_trashed(&self, username: &str, id: &str) -> Result<()> {
+        self.inner.purge_trashed(username, id).await?;
+        self.commit_always(username, format!("purge {}", id)).await
+    }
+
+    async fn user_file_written(&self, username: &str, filename: &str) -> Result<()> {
+        self.commit(username, format!("update {}", filename)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        models::metadata::Metadata,
+        storage::{fs::FsBlobStore, hash_blob},
+    };
+    use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+
+    fn post(attachment: &[u8]) -> Post {
+        Post {
+            name: "Note".to_string(),
+            path: "note.md".to_string(),
+            metadata: Metadata {
+                id: Some("note".to_string()),
+                extra: HashMap::new(),
+            },
+            content: "![[photo.png]]".to_string(),
+            attachments: Some(HashMap::from([(
+                "photo.png".to_string(),
+                BASE64.encode(attachment),
+            )])),
+            attachment_hashes: None,
+        }
+    }
+
+    fn head_message(repo: &Repository) -> String {
+        let commit = repo.head().unwrap().peel_to_commit().unwrap();
+        commit.message().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn commits_blobs_templates_and_purges() {
+        let dir = std::env::temp_dir().join(format!("dollpublish-git-{}", uuid::Uuid::new_v4()));
+        let blobs: Arc<dyn BlobStore> = Arc::new(FsBlobStore::new(&dir));
+        let inner = FsPostStore::new(dir.clone(), blobs.clone(), None);
+        let posts = GitPostStore::new(inner, blobs, dir.clone());
+
+        posts.save("alice", "note", &post(b"first")).await.unwrap();
+        let repo = Repository::open(dir.join("alice")).unwrap();
+        assert_eq!(head_message(&repo), "publish note");
+        posts.save("alice", "note", &post(b"second")).await.unwrap();
+        assert_eq!(head_message(&repo), "republish note");
+        let tree = repo.head().unwrap().peel_to_tree().unwrap();
+        let path = format!("{}/{}", BLOBS_DIR, hash_blob(b"second"));
+        let blob = repo
+            .find_blob(tree.get_path(Path::new(&path)).unwrap().id())
+            .unwrap();
+        assert_eq!(blob.content(), b"second");
+        // Only what the current posts reference is in the tree
+        let old = format!("{}/{}", BLOBS_DIR, hash_blob(b"first"));
+        assert!(tree.get_path(Path::new(&old)).is_err());
+
+        std::fs::write(dir.join("alice").join("template.html"), "{{{content}}}").unwrap();
+        posts
+            .user_file_written("alice", "template.html")
+            .await
+            .unwrap();
+        assert_eq!(head_message(&repo), "update template.html");
+
+        posts.delete("alice", "note").await.unwrap();
+        posts.purge_trashed("alice", "note").await.unwrap();
+        assert_eq!(head_message(&repo), "purge note");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,393 @@
+pub mod fs;
+pub mod gc;
+#[cfg(feature = "git")]
+pub mod git;
+#[cfg(feature = "s3")]
+pub mod s3;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+
+use crate::{
+    error::{AppError, Result},
+    models::{metadata::Metadata, post::Post, user::User},
+};
+use async_trait::async_trait;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+pub type AttachmentReader = Box<dyn AsyncRead + Send + Unpin>;
+
+pub enum AttachmentBody {
+    Stream(AttachmentReader),
+    /// The attachment can be fetched directly from this URL
+    #[cfg_attr(not(feature = "s3"), allow(dead_code))]
+    Redirect(String),
+}
+
+#[derive(Serialize, Clone)]
+pub struct PostSummary {
+    pub id: String,
+    pub name: String,
+    pub path: String,
+    /// Seconds since the unix epoch
+    pub modified: u64,
+}
+
+/// Maps each attachment filename of a post to the SHA-256 of its contents
+pub type Manifest = HashMap<String, String>;
+
+/// An unpublished post waiting in its user's trash
+#[derive(Serialize)]
+pub struct TrashEntry {
+    pub id: String,
+    pub name: String,
+    pub path: String,
+    /// When the post was unpublished, in seconds since the unix epoch
+    pub deleted: u64,
+    #[serde(skip)]
+    pub attachments: Manifest,
+}
+
+/// A snapshot of a post as it was saved
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Revision {
+    pub number: u64,
+    /// Seconds since the unix epoch
+    pub created: u64,
+    pub name: String,
+    pub path: String,
+    pub extra: HashMap<String, serde_json::Value>,
+    pub content: String,
+    pub attachments: Manifest,
+}
+
+#[derive(Serialize)]
+pub struct RevisionSummary {
+    pub number: u64,
+    pub created: u64,
+    pub name: String,
+}
+
+impl Revision {
+    pub fn new(number: u64, created: u64, post: &Post, manifest: &Manifest) -> Self {
+        Self {
+            number,
+            created,
+            name: post.name.clone(),
+            path: post.path.clone(),
+            extra: post.metadata.extra.clone(),
+            content: post.content.clone(),
+            attachments: manifest.clone(),
+        }
+    }
+
+    /// The post as of this revision, with attachments referring to its blobs
+    pub fn to_post(&self, id: &str) -> Post {
+        Post {
+            name: self.name.clone(),
+            path: self.path.clone(),
+            metadata: Metadata {
+                id: Some(id.to_string()),
+                extra: self.extra.clone(),
+            },
+            content: self.content.clone(),
+            attachments: None,
+            attachment_hashes: Some(self.attachments.clone()),
+        }
+    }
+
+    pub fn summary(&self) -> RevisionSummary {
+        RevisionSummary {
+            number: self.number,
+            created: self.created,
+            name: self.name.clone(),
+        }
+    }
+}
+
+/// Attachment filenames changed by a save, relative to the previous manifest
+#[derive(Serialize, Default)]
+pub struct AttachmentChanges {
+    pub added: Vec<String>,
+    pub updated: Vec<String>,
+    pub removed: Vec<String>,
+}
+
+impl AttachmentChanges {
+    pub fn between(old: &Manifest, new: &Manifest) -> Self {
+        let mut changes = Self::default();
+        for (filename, hash) in new {
+            match old.get(filename) {
+                None => changes.added.push(filename.clone()),
+                Some(old_hash) if old_hash != hash => changes.updated.push(filename.clone()),
+                Some(_) => {}
+            }
+        }
+        changes.removed = old
+            .keys()
+            .filter(|filename| !new.contains_key(*filename))
+            .cloned()
+            .collect();
+
+        changes.added.sort();
+        changes.updated.sort();
+        changes.removed.sort();
+        changes
+    }
+}
+
+pub struct BlobInfo {
+    pub hash: String,
+    /// Seconds since the unix epoch
+    pub modified: u64,
+}
+
+#[async_trait]
+pub trait PostStore: Send + Sync {
+    /// Saves a post, replacing its attachments with exactly those in `post`,
+    /// and records the result as a new revision
+    async fn save(&self, username: &str, id: &str, post: &Post) -> Result<AttachmentChanges>;
+    async fn load(&self, username: &str, id: &str) -> Result<Post>;
+    /// Loads a post without reading its attachment contents
+    async fn load_document(&self, username: &str, id: &str) -> Result<Post>;
+    async fn manifest(&self, username: &str, id: &str) -> Result<Manifest>;
+    /// Moves a post to its user's trash
+    async fn delete(&self, username: &str, id: &str) -> Result<()>;
+    async fn list(&self, username: &str) -> Result<Vec<PostSummary>>;
+    async fn usernames(&self) -> Result<Vec<String>>;
+    async fn attachment(&self, username: &str, id: &str, filename: &str) -> Result<AttachmentBody>;
+    /// Revisions of a post, oldest first
+    async fn revisions(&self, username: &str, id: &str) -> Result<Vec<RevisionSummary>>;
+    async fn revision(&self, username: &str, id: &str, number: u64) -> Result<Revision>;
+    async fn trash(&self, username: &str) -> Result<Vec<TrashEntry>>;
+    /// Users with at least one post in the trash
+    async fn trash_usernames(&self) -> Result<Vec<String>>;
+    /// Moves a post back out of the trash, unless a post with its id exists
+    async fn restore_trashed(&self, username: &str, id: &str) -> Result<()>;
+    /// Permanently deletes a trashed post, along with its revisions unless a
+    /// post with the same id has since been published
+    async fn purge_trashed(&self, username: &str, id: &str) -> Result<()>;
+
+    /// Called after one of a user's own files, such as their template, was
+    /// written to their directory
+    async fn user_file_written(&self, _username: &str, _filename: &str) -> Result<()> {
+        Ok(())
+    }
+
+    async fn attachment_names(&self, username: &str, id: &str) -> Result<Vec<String>> {
+        Ok(self.manifest(username, id).await?.into_keys().collect())
+    }
+
+    /// Blob hashes referenced by a user's own posts and trash
+    async fn user_blobs(&self, username: &str) -> Result<HashSet<String>> {
+        let mut hashes = HashSet::new();
+        for post in self.list(username).await? {
+            hashes.extend(self.manifest(username, &post.id).await?.into_values());
+        }
+        for entry in self.trash(username).await? {
+            hashes.extend(entry.attachments.into_values());
+        }
+        Ok(hashes)
+    }
+
+    /// Every blob hash referenced by any post, trashed post or kept revision
+    async fn referenced_blobs(&self) -> Result<HashSet<String>> {
+        let mut hashes = HashSet::new();
+        let mut posts = HashSet::new();
+        for username in self.usernames().await? {
+            for post in self.list(&username).await? {
+                hashes.extend(self.manifest(&username, &post.id).await?.into_values());
+                posts.insert((username.clone(), post.id));
+            }
+        }
+        for username in self.trash_usernames().await? {
+            for entry in self.trash(&username).await? {
+                hashes.extend(entry.attachments.into_values());
+                posts.insert((username.clone(), entry.id));
+            }
+        }
+
+        for (username, id) in posts {
+            for revision in self.revisions(&username, &id).await? {
+                let revision = self.revision(&username, &id, revision.number).await?;
+                hashes.extend(revision.attachments.into_values());
+            }
+        }
+        Ok(hashes)
+    }
+}
+
+/// Content-addressed storage for attachment bytes, keyed by SHA-256
+#[async_trait]
+pub trait BlobStore: Send + Sync {
+    async fn put(&self, hash: &str, data: Vec<u8>) -> Result<()>;
+
+    /// Stores a blob read from `reader`, failing with
+    /// [`AppError::HashMismatch`] unless its contents hash to `hash`
+    async fn put_stream(&self, hash: &str, mut reader: AttachmentReader) -> Result<()> {
+        check_hash(hash)?;
+        let mut data = Vec::new();
+        reader
+            .read_to_end(&mut data)
+            .await
+            .map_err(|e| AppError::Internal(e.to_string()))?;
+        if hash_blob(&data) != hash {
+            return Err(AppError::HashMismatch);
+        }
+        self.put(hash, data).await
+    }
+    async fn exists(&self, hash: &str) -> Result<bool>;
+    /// Refreshes a stored blob's modified time, returning whether it is
+    /// stored. Called when a post is about to reference the blob, so that
+    /// garbage collection gives it another grace period.
+    async fn touch(&self, hash: &str) -> Result<bool>;
+    /// `content_type` is used when the blob is served from elsewhere
+    async fn get(&self, hash: &str, content_type: &str) -> Result<AttachmentBody>;
+    async fn read(&self, hash: &str) -> Result<Vec<u8>>;
+    async fn delete(&self, hash: &str) -> Result<()>;
+    async fn list(&self) -> Result<Vec<BlobInfo>>;
+}
+
+#[async_trait]
+pub trait UserStore: Send + Sync {
+    /// Returns `None` when no users have been stored yet
+    async fn load(&self) -> Result<Option<HashMap<String, User>>>;
+    async fn save(&self, users: &HashMap<String, User>) -> Result<()>;
+
+    /// A file that changes when users are edited, possibly by another process
+    fn watch_path(&self) -> Option<PathBuf> {
+        None
+    }
+
+    /// For stores whose [`UserStore::watch_path`] also changes for other
+    /// reasons, a number that changes whenever users are saved
+    async fn version(&self) -> Result<Option<u64>> {
+        Ok(None)
+    }
+}
+
+pub fn hash_blob(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+/// Rejects anything that is not a lowercase hex SHA-256
+pub fn check_hash(hash: &str) -> Result<()> {
+    if hash.len() != 64 || !hash.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')) {
+        return Err(AppError::InvalidFile);
+    }
+    Ok(())
+}
+
+/// Hashes and stores any attachments in `post` that the blob store does not
+/// already have, returning the post's new manifest. Attachments
+/// given only by hash must already have been uploaded.
+pub async fn store_attachments(blobs: &dyn BlobStore, post: &Post) -> Result<Manifest> {
+    let mut manifest = Manifest::new();
+    if let Some(hashes) = &post.attachment_hashes {
+        for (filename, hash) in hashes {
+            check_segment(filename)?;
+            check_hash(hash)?;
+            if !blobs.touch(hash).await? {
+                return Err(AppError::MissingBlob(hash.clone()));
+            }
+            manifest.insert(filename.clone(), hash.clone());
+        }
+    }
+    if let Some(attachments) = &post.attachments {
+        // Decode everything up front so a bad payload stores nothing
+        let mut decoded = Vec::new();
+        for (filename, content) in attachments {
+            check_segment(filename)?;
+            let data = BASE64
+                .decode(content)
+                .map_err(|_| AppError::InvalidAttachment(filename.clone()))?;
+            decoded.push((filename, data));
+        }
+
+        for (filename, data) in decoded {
+            let hash = hash_blob(&data);
+            if !blobs.touch(&hash).await? {
+                blobs.put(&hash, data).await?;
+            }
+            manifest.insert(filename.clone(), hash);
+        }
+    }
+    Ok(manifest)
+}
+
+/// Reads every attachment in `manifest` back into the base64 form used by [`Post`]
+pub async fn read_attachments(
+    blobs: &dyn BlobStore,
+    manifest: &Manifest,
+) -> Result<Option<HashMap<String, String>>> {
+    if manifest.is_empty() {
+        return Ok(None);
+    }
+
+    let mut attachments = HashMap::new();
+    for (filename, hash) in manifest {
+        attachments.insert(filename.clone(), BASE64.encode(blobs.read(hash).await?));
+    }
+    Ok(Some(attachments))
+}
+
+/// Rejects names that could escape their directory or are not a single path
+/// segment at all
+pub fn check_segment(segment: &str) -> Result<()> {
+    if segment.is_empty()
+        || segment == "."
+        || segment == ".."
+        || segment.contains(['/', '\\', '\0'])
+    {
+        return Err(AppError::InvalidFile);
+    }
+    Ok(())
+}
+
+/// Rejects usernames and post ids that [`check_segment`] would, and those
+/// starting with `.`, which are kept for the stores' own directories such as
+/// `.blobs`, `.trash` and in-progress saves
+pub fn check_id(segment: &str) -> Result<()> {
+    check_segment(segment)?;
+    if segment.starts_with('.') {
+        return Err(AppError::InvalidFile);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest(entries: &[(&str, &str)]) -> Manifest {
+        entries
+            .iter()
+            .map(|(filename, hash)| (filename.to_string(), hash.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn compares_manifests() {
+        let old = manifest(&[("same.png", "a"), ("edited.png", "b"), ("gone.png", "c")]);
+        let new = manifest(&[("same.png", "a"), ("edited.png", "d"), ("new.png", "c")]);
+
+        let changes = AttachmentChanges::between(&old, &new);
+        assert_eq!(changes.added, ["new.png"]);
+        assert_eq!(changes.updated, ["edited.png"]);
+        assert_eq!(changes.removed, ["gone.png"]);
+
+        let unchanged = AttachmentChanges::between(&new, &new);
+        assert!(unchanged.added.is_empty());
+        assert!(unchanged.updated.is_empty());
+        assert!(unchanged.removed.is_empty());
+
+        let first = AttachmentChanges::between(&Manifest::new(), &new);
+        assert_eq!(first.added, ["edited.png", "new.png", "same.png"]);
+    }
+}
@@ -0,0 +1,932 @@
+use super::{
+    check_hash, check_id,
+    fs::{FsBlobStore, FsPostStore, FsUserStore},
+    hash_blob, read_attachments, store_attachments, AttachmentBody, AttachmentChanges, BlobInfo,
+    BlobStore, Manifest, PostStore, PostSummary, Revision, RevisionSummary, TrashEntry, UserStore,
+};
+use crate::{
+    error::{AppError, Result},
+    models::{metadata::Metadata, post::Post, user::User},
+};
+use async_trait::async_trait;
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension, MAIN_DB};
+use std::{
+    collections::HashMap,
+    io::{Cursor, Read},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::mpsc;
+use tokio_util::io::StreamReader;
+
+/// How much of a blob is read from the database at a time when streaming it
+const CHUNK_SIZE: usize = 64 * 1024;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS posts (
+    username TEXT NOT NULL,
+    id TEXT NOT NULL,
+    name TEXT NOT NULL,
+    path TEXT NOT NULL,
+    extra TEXT NOT NULL,
+    content TEXT NOT NULL,
+    attachments TEXT NOT NULL DEFAULT '{}',
+    modified INTEGER NOT NULL,
+    PRIMARY KEY (username, id)
+);
+CREATE TABLE IF NOT EXISTS trash (
+    username TEXT NOT NULL,
+    id TEXT NOT NULL,
+    name TEXT NOT NULL,
+    path TEXT NOT NULL,
+    extra TEXT NOT NULL,
+    content TEXT NOT NULL,
+    attachments TEXT NOT NULL,
+    deleted INTEGER NOT NULL,
+    PRIMARY KEY (username, id)
+);
+CREATE TABLE IF NOT EXISTS revisions (
+    username TEXT NOT NULL,
+    id TEXT NOT NULL,
+    number INTEGER NOT NULL,
+    name TEXT NOT NULL,
+    path TEXT NOT NULL,
+    extra TEXT NOT NULL,
+    content TEXT NOT NULL,
+    attachments TEXT NOT NULL,
+    created INTEGER NOT NULL,
+    PRIMARY KEY (username, id, number)
+);
+CREATE TABLE IF NOT EXISTS blobs (
+    hash TEXT PRIMARY KEY,
+    data BLOB NOT NULL,
+    created INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS users (
+    username TEXT PRIMARY KEY,
+    data TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS meta (
+    key TEXT PRIMARY KEY,
+    value TEXT NOT NULL
+);
+";
+
+/// A shared SQLite database holding users and, unless another attachment
+/// store is configured, attachment blobs
+#[derive(Clone)]
+pub struct SqliteStore {
+    conn: Arc<Mutex<Connection>>,
+    path: PathBuf,
+}
+
+/// Stores posts and their attachment manifests in the `posts` table of a
+/// [`SqliteStore`], their history in `revisions` and unpublished posts in
+/// `trash`
+pub struct SqlitePostStore {
+    db: SqliteStore,
+    blobs: Arc<dyn BlobStore>,
+    /// How many revisions to keep per post; `None` keeps them all
+    revision_limit: Option<usize>,
+}
+
+fn internal(e: impl ToString) -> AppError {
+    AppError::Internal(e.to_string())
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+fn read_manifest(conn: &Connection, username: &str, id: &str) -> Result<Manifest> {
+    let manifest = conn
+        .query_row(
+            "SELECT attachments FROM posts WHERE username = ?1 AND id = ?2",
+            params![username, id],
+            |r| r.get::<_, String>(0),
+        )
+        .optional()
+        .map_err(internal)?;
+
+    match manifest {
+        Some(manifest) => serde_json::from_str(&manifest).map_err(internal),
+        None => Ok(Manifest::new()),
+    }
+}
+
+/// Writes a post into `table`, which is `posts` or `trash`; `time` is when it
+/// was modified or deleted respectively
+fn insert_post(
+    conn: &Connection,
+    table: &str,
+    username: &str,
+    id: &str,
+    post: &Post,
+    manifest: &Manifest,
+    time: i64,
+) -> Result<()> {
+    let time_column = if table == "trash" {
+        "deleted"
+    } else {
+        "modified"
+    };
+    conn.execute(
+        &format!(
+            "INSERT OR REPLACE INTO {} (username, id, name, path, extra, content, attachments, {})
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            table, time_column
+        ),
+        params![
+            username,
+            id,
+            post.name,
+            post.path,
+            serde_json::to_string(&post.metadata.extra).unwrap(),
+            post.content,
+            serde_json::to_string(manifest).unwrap(),
+            time,
+        ],
+    )
+    .map_err(internal)?;
+    Ok(())
+}
+
+fn insert_revision(conn: &Connection, username: &str, id: &str, revision: &Revision) -> Result<()> {
+    conn.execute(
+        "INSERT OR REPLACE INTO revisions
+            (username, id, number, name, path, extra, content, attachments, created)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+        params![
+            username,
+            id,
+            revision.number as i64,
+            revision.name,
+            revision.path,
+            serde_json::to_string(&revision.extra).unwrap(),
+            revision.content,
+            serde_json::to_string(&revision.attachments).unwrap(),
+            revision.created as i64,
+        ],
+    )
+    .map_err(internal)?;
+    Ok(())
+}
+
+impl SqliteStore {
+    pub fn open(path: &Path) -> Result<Self> {
+        let conn = Connection::open(path).map_err(internal)?;
+        conn.execute_batch("PRAGMA journal_mode = WAL;")
+            .map_err(internal)?;
+        conn.execute_batch(SCHEMA).map_err(internal)?;
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+            path: path.to_path_buf(),
+        })
+    }
+
+    async fn with_conn<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<T> + Send + 'static,
+    {
+        let conn = self.conn.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut conn = conn.lock().map_err(internal)?;
+            f(&mut conn)
+        })
+        .await
+        .map_err(internal)?
+    }
+}
+
+impl SqlitePostStore {
+    pub fn new(db: SqliteStore, blobs: Arc<dyn BlobStore>, revision_limit: Option<usize>) -> Self {
+        Self {
+            db,
+            blobs,
+            revision_limit,
+        }
+    }
+
+    /// Imports users, posts, their revisions and the trash from a filesystem
+    /// data dir, once per database. The data dir is only read, so a post
+    /// whose save was interrupted by a crash there is only imported once the
+    /// fs backend has recovered it.
+    pub async fn import_fs(&self, data_dir: &Path) -> Result<()> {
+        let imported = self
+            .db
+            .with_conn(|conn| {
+                conn.query_row("SELECT value FROM meta WHERE key = 'fs_import'", [], |r| {
+                    r.get::<_, String>(0)
+                })
+                .optional()
+                .map_err(internal)
+            })
+            .await?;
+        if imported.is_some() {
+            return Ok(());
+        }
+
+        if self.db.load().await?.is_none() {
+            if let Some(users) = FsUserStore::new(data_dir).load().await? {
+                self.db.save(&users).await?;
+                eprintln!("Imported {} users from users.json", users.len());
+            }
+        }
+
+        let fs_blobs = Arc::new(FsBlobStore::new(data_dir));
+        let fs_store = FsPostStore::new(data_dir.to_path_buf(), fs_blobs.clone(), None);
+        let mut ids = Vec::new();
+
+        let mut posts = 0;
+        for username in fs_store.usernames().await? {
+            for summary in fs_store.list(&username).await? {
+                let post = fs_store.load_document(&username, &summary.id).await?;
+                let mut manifest = fs_store.manifest(&username, &summary.id).await?;
+                for (filename, data) in fs_store.legacy_attachments(&username, &summary.id).await? {
+                    let hash = hash_blob(&data);
+                    if !self.blobs.touch(&hash).await? {
+                        self.blobs.put(&hash, data).await?;
+                    }
+                    manifest.entry(filename).or_insert(hash);
+                }
+                self.copy_blobs(fs_blobs.as_ref(), &manifest).await?;
+
+                let (owner, id, modified) = (
+                    username.clone(),
+                    summary.id.clone(),
+                    summary.modified as i64,
+                );
+                self.db
+                    .with_conn(move |conn| {
+                        insert_post(conn, "posts", &owner, &id, &post, &manifest, modified)
+                    })
+                    .await?;
+                ids.push((username.clone(), summary.id));
+                posts += 1;
+            }
+        }
+
+        let mut trashed = 0;
+        for username in fs_store.trash_usernames().await? {
+            for entry in fs_store.trash(&username).await? {
+                let post = fs_store.load_trashed(&username, &entry.id).await?;
+                self.copy_blobs(fs_blobs.as_ref(), &entry.attachments)
+                    .await?;
+
+                let (owner, id, deleted) = (username.clone(), entry.id.clone(), entry.deleted);
+                self.db
+                    .with_conn(move |conn| {
+                        insert_post(
+                            conn,
+                            "trash",
+                            &owner,
+                            &id,
+                            &post,
+                            &entry.attachments,
+                            deleted as i64,
+                        )
+                    })
+                    .await?;
+                ids.push((username.clone(), entry.id));
+                trashed += 1;
+            }
+        }
+
+        ids.sort();
+        ids.dedup();
+        let mut revisions = 0;
+        for (username, id) in ids {
+            for summary in fs_store.revisions(&username, &id).await? {
+                let revision = fs_store.revision(&username, &id, summary.number).await?;
+                self.copy_blobs(fs_blobs.as_ref(), &revision.attachments)
+                    .await?;
+
+                let (owner, post_id) = (username.clone(), id.clone());
+                self.db
+                    .with_conn(move |conn| insert_revision(conn, &owner, &post_id, &revision))
+                    .await?;
+                revisions += 1;
+            }
+        }
+        eprintln!(
+            "Imported {} posts, {} trashed posts and {} revisions from {}",
+            posts,
+            trashed,
+            revisions,
+            data_dir.display()
+        );
+
+        self.db
+            .with_conn(|conn| {
+                conn.execute(
+                    "INSERT INTO meta (key, value) VALUES ('fs_import', ?1)",
+                    params![now().to_string()],
+                )
+                .map_err(internal)?;
+                Ok(())
+            })
+            .await
+    }
+
+    /// Copies the blobs in `manifest` that this store does not have yet
+    async fn copy_blobs(&self, from: &dyn BlobStore, manifest: &Manifest) -> Result<()> {
+        for hash in manifest.values() {
+            if !self.blobs.touch(hash).await? {
+                self.blobs.put(hash, from.read(hash).await?).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl PostStore for SqlitePostStore {
+    async fn save(&self, username: &str, id: &str, post: &Post) -> Result<AttachmentChanges> {
+        check_id(username)?;
+        check_id(id)?;
+
+        let manifest = store_attachments(self.blobs.as_ref(), post).await?;
+
+        let (owner, post_id, document) = (username.to_string(), id.to_string(), post.clone());
+        let revision_limit = self.revision_limit;
+        self.db
+            .with_conn(move |conn| {
+                let tx = conn.transaction().map_err(internal)?;
+                let old_manifest = read_manifest(&tx, &owner, &post_id)?;
+
+                insert_post(&tx, "posts", &owner, &post_id, &document, &manifest, now())?;
+
+                let number: i64 = tx
+                    .query_row(
+                        "SELECT COALESCE(MAX(number), 0) + 1 FROM revisions WHERE username = ?1 AND id = ?2",
+                        params![owner, post_id],
+                        |r| r.get(0),
+                    )
+                    .map_err(internal)?;
+                let revision = Revision::new(number as u64, now() as u64, &document, &manifest);
+                insert_revision(&tx, &owner, &post_id, &revision)?;
+                if let Some(limit) = revision_limit {
+                    tx.execute(
+                        "DELETE FROM revisions WHERE username = ?1 AND id = ?2 AND number <= ?3",
+                        params![owner, post_id, number - limit as i64],
+                    )
+                    .map_err(internal)?;
+                }
+
+                tx.commit().map_err(internal)?;
+                Ok(AttachmentChanges::between(&old_manifest, &manifest))
+            })
+            .await
+    }
+
+    async fn load(&self, username: &str, id: &str) -> Result<Post> {
+        let mut post = self.load_document(username, id).await?;
+        let manifest = self.manifest(username, id).await?;
+        post.attachments = read_attachments(self.blobs.as_ref(), &manifest).await?;
+        Ok(post)
+    }
+
+    async fn load_document(&self, username: &str, id: &str) -> Result<Post> {
+        let (username, id) = (username.to_string(), id.to_string());
+        self.db
+            .with_conn(move |conn| {
+                conn.query_row(
+                    "SELECT name, path, extra, content FROM posts WHERE username = ?1 AND id = ?2",
+                    params![username, id],
+                    |r| {
+                        Ok((
+                            r.get::<_, String>(0)?,
+                            r.get::<_, String>(1)?,
+                            r.get::<_, String>(2)?,
+                            r.get::<_, String>(3)?,
+                        ))
+                    },
+                )
+                .optional()
+                .map_err(internal)?
+                .ok_or(AppError::NotFound)
+                .and_then(|(name, path, extra, content)| {
+                    Ok(Post {
+                        name,
+                        path,
+                        metadata: Metadata {
+                            id: Some(id),
+                            extra: serde_json::from_str(&extra).map_err(internal)?,
+                        },
+                        content,
+                        attachments: None,
+                        attachment_hashes: None,
+                    })
+                })
+            })
+            .await
+    }
+
+    async fn manifest(&self, username: &str, id: &str) -> Result<Manifest> {
+        let (username, id) = (username.to_string(), id.to_string());
+        self.db
+            .with_conn(move |conn| read_manifest(conn, &username, &id))
+            .await
+    }
+
+    async fn delete(&self, username: &str, id: &str) -> Result<()> {
+        let (owner, post_id) = (username.to_string(), id.to_string());
+        self.db
+            .with_conn(move |conn| {
+                let tx = conn.transaction().map_err(internal)?;
+                tx.execute(
+                    "INSERT OR REPLACE INTO trash
+                        (username, id, name, path, extra, content, attachments, deleted)
+                     SELECT username, id, name, path, extra, content, attachments, ?3
+                     FROM posts WHERE username = ?1 AND id = ?2",
+                    params![owner, post_id, now()],
+                )
+                .map_err(internal)?;
+                let deleted = tx
+                    .execute(
+                        "DELETE FROM posts WHERE username = ?1 AND id = ?2",
+                        params![owner, post_id],
+                    )
+                    .map_err(internal)?;
+                if deleted == 0 {
+                    return Err(AppError::NotFound);
+                }
+                tx.commit().map_err(internal)
+            })
+            .await
+    }
+
+    async fn list(&self, username: &str) -> Result<Vec<PostSummary>> {
+        let username = username.to_string();
+        self.db
+            .with_conn(move |conn| {
+                let mut stmt = conn
+                    .prepare(
+                        "SELECT id, name, path, modified FROM posts WHERE username = ?1 ORDER BY id",
+                    )
+                    .map_err(internal)?;
+                let rows = stmt
+                    .query_map(params![username], |r| {
+                        Ok(PostSummary {
+                            id: r.get(0)?,
+                            name: r.get(1)?,
+                            path: r.get(2)?,
+                            modified: r.get::<_, i64>(3)? as u64,
+                        })
+                    })
+                    .map_err(internal)?;
+                rows.collect::<rusqlite::Result<Vec<_>>>().map_err(internal)
+            })
+            .await
+    }
+
+    async fn usernames(&self) -> Result<Vec<String>> {
+        self.db
+            .with_conn(|conn| {
+                let mut stmt = conn
+                    .prepare("SELECT DISTINCT username FROM posts")
+                    .map_err(internal)?;
+                let rows = stmt
+                    .query_map([], |r| r.get::<_, String>(0))
+                    .map_err(internal)?;
+                rows.collect::<rusqlite::Result<Vec<_>>>().map_err(internal)
+            })
+            .await
+    }
+
+    async fn attachment(&self, username: &str, id: &str, filename: &str) -> Result<AttachmentBody> {
+        let manifest = self.manifest(username, id).await?;
+        let hash = manifest.get(filename).ok_or(AppError::NotFound)?;
+        let content_type = mime_guess::from_path(filename).first_or_octet_stream();
+        self.blobs.get(hash, content_type.as_ref()).await
+    }
+
+    async fn revisions(&self, username: &str, id: &str) -> Result<Vec<RevisionSummary>> {
+        let (username, id) = (username.to_string(), id.to_string());
+        self.db
+            .with_conn(move |conn| {
+                let mut stmt = conn
+                    .prepare(
+                        "SELECT number, created, name FROM revisions
+                         WHERE username = ?1 AND id = ?2 ORDER BY number",
+                    )
+                    .map_err(internal)?;
+                let rows = stmt
+                    .query_map(params![username, id], |r| {
+                        Ok(RevisionSummary {
+                            number: r.get::<_, i64>(0)? as u64,
+                            created: r.get::<_, i64>(1)? as u64,
+                            name: r.get(2)?,
+                        })
+                    })
+                    .map_err(internal)?;
+                rows.collect::<rusqlite::Result<Vec<_>>>().map_err(internal)
+            })
+            .await
+    }
+
+    async fn revision(&self, username: &str, id: &str, number: u64) -> Result<Revision> {
+        let (username, id) = (username.to_string(), id.to_string());
+        self.db
+            .with_conn(move |conn| {
+                let row = conn
+                    .query_row(
+                        "SELECT created, name, path, extra, content, attachments FROM revisions
+                         WHERE username = ?1 AND id = ?2 AND number = ?3",
+                        params![username, id, number as i64],
+                        |r| {
+                            Ok((
+                                r.get::<_, i64>(0)?,
+                                r.get::<_, String>(1)?,
+                                r.get::<_, String>(2)?,
+                                r.get::<_, String>(3)?,
+                                r.get::<_, String>(4)?,
+                                r.get::<_, String>(5)?,
+                            ))
+                        },
+                    )
+                    .optional()
+                    .map_err(internal)?;
+                let (created, name, path, extra, content, attachments) =
+                    row.ok_or(AppError::NotFound)?;
+
+                Ok(Revision {
+                    number,
+                    created: created as u64,
+                    name,
+                    path,
+                    extra: serde_json::from_str(&extra).map_err(internal)?,
+                    content,
+                    attachments: serde_json::from_str(&attachments).map_err(internal)?,
+                })
+            })
+            .await
+    }
+
+    async fn trash(&self, username: &str) -> Result<Vec<TrashEntry>> {
+        let username = username.to_string();
+        self.db
+            .with_conn(move |conn| {
+                let mut stmt = conn
+                    .prepare(
+                        "SELECT id, name, path, deleted, attachments FROM trash
+                         WHERE username = ?1 ORDER BY id",
+                    )
+                    .map_err(internal)?;
+                let rows = stmt
+                    .query_map(params![username], |r| {
+                        Ok((
+                            r.get::<_, String>(0)?,
+                            r.get::<_, String>(1)?,
+                            r.get::<_, String>(2)?,
+                            r.get::<_, i64>(3)?,
+                            r.get::<_, String>(4)?,
+                        ))
+                    })
+                    .map_err(internal)?;
+
+                let mut trash = Vec::new();
+                for row in rows {
+                    let (id, name, path, deleted, attachments) = row.map_err(internal)?;
+                    trash.push(TrashEntry {
+                        id,
+                        name,
+                        path,
+                        deleted: deleted as u64,
+                        attachments: serde_json::from_str(&attachments).map_err(internal)?,
+                    });
+                }
+                Ok(trash)
+            })
+            .await
+    }
+
+    async fn trash_usernames(&self) -> Result<Vec<String>> {
+        self.db
+            .with_conn(|conn| {
+                let mut stmt = conn
+                    .prepare("SELECT DISTINCT username FROM trash")
+                    .map_err(internal)?;
+                let rows = stmt
+                    .query_map([], |r| r.get::<_, String>(0))
+                    .map_err(internal)?;
+                rows.collect::<rusqlite::Result<Vec<_>>>().map_err(internal)
+            })
+            .await
+    }
+
+    async fn restore_trashed(&self, username: &str, id: &str) -> Result<()> {
+        let (username, id) = (username.to_string(), id.to_string());
+        self.db
+            .with_conn(move |conn| {
+                let tx = conn.transaction().map_err(internal)?;
+                let published = tx
+                    .query_row(
+                        "SELECT 1 FROM posts WHERE username = ?1 AND id = ?2",
+                        params![username, id],
+                        |_| Ok(()),
+                    )
+                    .optional()
+                    .map_err(internal)?;
+                if published.is_some() {
+                    return Err(AppError::Conflict(format!(
+                        "A post with the id {} is already published",
+                        id
+                    )));
+                }
+
+                let restored = tx
+                    .execute(
+                        "INSERT INTO posts
+                            (username, id, name, path, extra, content, attachments, modified)
+                         SELECT username, id, name, path, extra, content, attachments, ?3
+                         FROM trash WHERE username = ?1 AND id = ?2",
+                        params![username, id, now()],
+                    )
+                    .map_err(internal)?;
+                if restored == 0 {
+                    return Err(AppError::NotFound);
+                }
+                tx.execute(
+                    "DELETE FROM trash WHERE username = ?1 AND id = ?2",
+                    params![username, id],
+                )
+                .map_err(internal)?;
+                tx.commit().map_err(internal)
+            })
+            .await
+    }
+
+    async fn purge_trashed(&self, username: &str, id: &str) -> Result<()> {
+        let (username, id) = (username.to_string(), id.to_string());
+        self.db
+            .with_conn(move |conn| {
+                let tx = conn.transaction().map_err(internal)?;
+                let purged = tx
+                    .execute(
+                        "DELETE FROM trash WHERE username = ?1 AND id = ?2",
+                        params![username, id],
+                    )
+                    .map_err(internal)?;
+                if purged == 0 {
+                    return Err(AppError::NotFound);
+                }
+                tx.execute(
+                    "DELETE FROM revisions WHERE username = ?1 AND id = ?2
+                     AND NOT EXISTS (SELECT 1 FROM posts WHERE username = ?1 AND id = ?2)",
+                    params![username, id],
+                )
+                .map_err(internal)?;
+                tx.commit().map_err(internal)
+            })
+            .await
+    }
+}
+
+/// Sends the blob in row `row_id` to `tx` a chunk at a time, stopping early if
+/// the receiver goes away
+fn stream_blob(path: &Path, row_id: i64, tx: &mpsc::Sender<std::io::Result<Cursor<Vec<u8>>>>) {
+    let opened = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .map_err(std::io::Error::other);
+    let conn = match opened {
+        Ok(conn) => conn,
+        Err(e) => {
+            let _ = tx.blocking_send(Err(e));
+            return;
+        }
+    };
+    let mut blob = match conn.blob_open(MAIN_DB, "blobs", "data", row_id, true) {
+        Ok(blob) => blob,
+        Err(e) => {
+            let _ = tx.blocking_send(Err(std::io::Error::other(e)));
+            return;
+        }
+    };
+
+    loop {
+        let mut chunk = vec![0; CHUNK_SIZE];
+        let chunk = match blob.read(&mut chunk) {
+            Ok(0) => return,
+            Ok(n) => {
+                chunk.truncate(n);
+                Ok(Cursor::new(chunk))
+            }
+            Err(e) => Err(e),
+        };
+        let failed = chunk.is_err();
+        if tx.blocking_send(chunk).is_err() || failed {
+            return;
+        }
+    }
+}
+
+#[async_trait]
+impl BlobStore for SqliteStore {
+    async fn put(&self, hash: &str, data: Vec<u8>) -> Result<()> {
+        check_hash(hash)?;
+        let hash = hash.to_string();
+        self.with_conn(move |conn| {
+            conn.execute(
+                "INSERT INTO blobs (hash, data, created) VALUES (?1, ?2, ?3)
+                 ON CONFLICT (hash) DO UPDATE SET created = excluded.created",
+                params![hash, data, now()],
+            )
+            .map_err(internal)?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn exists(&self, hash: &str) -> Result<bool> {
+        let hash = hash.to_string();
+        self.with_conn(move |conn| {
+            conn.query_row("SELECT 1 FROM blobs WHERE hash = ?1", [hash], |_| Ok(()))
+                .optional()
+                .map(|found| found.is_some())
+                .map_err(internal)
+        })
+        .await
+    }
+
+    async fn touch(&self, hash: &str) -> Result<bool> {
+        let hash = hash.to_string();
+        self.with_conn(move |conn| {
+            conn.execute(
+                "UPDATE blobs SET created = ?2 WHERE hash = ?1",
+                params![hash, now()],
+            )
+            .map(|updated| updated > 0)
+            .map_err(internal)
+        })
+        .await
+    }
+
+    async fn get(&self, hash: &str, _content_type: &str) -> Result<AttachmentBody> {
+        check_hash(hash)?;
+        let hash = hash.to_string();
+        let row_id = self
+            .with_conn(move |conn| {
+                conn.query_row(
+                    "SELECT rowid FROM blobs WHERE hash = ?1",
+                    params![hash],
+                    |r| r.get::<_, i64>(0),
+                )
+                .optional()
+                .map_err(internal)?
+                .ok_or(AppError::NotFound)
+            })
+            .await?;
+
+        // Read on a connection of its own, so a slow download does not hold
+        // up everything else waiting for the shared one
+        let (tx, mut rx) = mpsc::channel(4);
+        let path = self.path.clone();
+        tokio::task::spawn_blocking(move || stream_blob(&path, row_id, &tx));
+
+        let chunks = futures_util::stream::poll_fn(move |cx| rx.poll_recv(cx));
+        Ok(AttachmentBody::Stream(Box::new(StreamReader::new(chunks))))
+    }
+
+    async fn read(&self, hash: &str) -> Result<Vec<u8>> {
+        let hash = hash.to_string();
+        self.with_conn(move |conn| {
+            conn.query_row(
+                "SELECT data FROM blobs WHERE hash = ?1",
+                params![hash],
+                |r| r.get::<_, Vec<u8>>(0),
+            )
+            .optional()
+            .map_err(internal)?
+            .ok_or(AppError::NotFound)
+        })
+        .await
+    }
+
+    async fn delete(&self, hash: &str) -> Result<()> {
+        let hash = hash.to_string();
+        self.with_conn(move |conn| {
+            conn.execute("DELETE FROM blobs WHERE hash = ?1", params![hash])
+                .map_err(internal)?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn list(&self) -> Result<Vec<BlobInfo>> {
+        self.with_conn(|conn| {
+            let mut stmt = conn
+                .prepare("SELECT hash, created FROM blobs")
+                .map_err(internal)?;
+            let rows = stmt
+                .query_map([], |r| {
+                    Ok(BlobInfo {
+                        hash: r.get(0)?,
+                        modified: r.get::<_, i64>(1)? as u64,
+                    })
+                })
+                .map_err(internal)?;
+            rows.collect::<rusqlite::Result<Vec<_>>>().map_err(internal)
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl UserStore for SqliteStore {
+    async fn load(&self) -> Result<Option<HashMap<String, User>>> {
+        self.with_conn(|conn| {
+            let mut stmt = conn
+                .prepare("SELECT username, data FROM users")
+                .map_err(internal)?;
+            let rows = stmt
+                .query_map([], |r| Ok((r.get::<_, String>(0)?, r.get::<_, String>(1)?)))
+                .map_err(internal)?;
+
+            let mut users = HashMap::new();
+            for row in rows {
+                let (username, data) = row.map_err(internal)?;
+                users.insert(username, serde_json::from_str(&data).map_err(internal)?);
+            }
+            Ok(if users.is_empty() { None } else { Some(users) })
+        })
+        .await
+    }
+
+    async fn save(&self, users: &HashMap<String, User>) -> Result<()> {
+        let users = users.clone();
+        self.with_conn(move |conn| {
+            let tx = conn.transaction().map_err(internal)?;
+            tx.execute("DELETE FROM users", []).map_err(internal)?;
+            tx.execute(
+                "INSERT INTO meta (key, value) VALUES ('users_version', '1')
+                 ON CONFLICT (key) DO UPDATE SET value = CAST(value AS INTEGER) + 1",
+                [],
+            )
+            .map_err(internal)?;
+            for (username, user) in users {
+                tx.execute(
+                    "INSERT INTO users (username, data) VALUES (?1, ?2)",
+                    params![username, serde_json::to_string(&user).unwrap()],
+                )
+                .map_err(internal)?;
+            }
+            tx.commit().map_err(internal)
+        })
+        .await
+    }
+
+    fn watch_path(&self) -> Option<PathBuf> {
+        Some(self.path.clone())
+    }
+
+    /// Publishing writes to the same database, so the watcher only reloads
+    /// users when this changes
+    async fn version(&self) -> Result<Option<u64>> {
+        self.with_conn(|conn| {
+            let version = conn
+                .query_row(
+                    "SELECT value FROM meta WHERE key = 'users_version'",
+                    [],
+                    |r| r.get::<_, String>(0),
+                )
+                .optional()
+                .map_err(internal)?;
+            Ok(Some(version.and_then(|v| v.parse().ok()).unwrap_or(0)))
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn users_version_changes_only_with_users() {
+        let dir = std::env::temp_dir().join(format!("dollpublish-sqlite-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let db = SqliteStore::open(&dir.join("dollpublish.db")).unwrap();
+        assert_eq!(db.version().await.unwrap(), Some(0));
+
+        db.save(&HashMap::from([("doll".to_string(), User::default())]))
+            .await
+            .unwrap();
+        let saved = db.version().await.unwrap();
+        assert_ne!(saved, Some(0));
+
+        db.with_conn(|conn| {
+            conn.execute("INSERT INTO meta (key, value) VALUES ('other', 'x')", [])
+                .map_err(internal)
+        })
+        .await
+        .unwrap();
+        assert_eq!(db.version().await.unwrap(), saved);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,223 @@
+use crate::{
+    error::{AppError, Result},
+    utils::auth::Auth,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    fs::{self, OpenOptions},
+    io::AsyncWriteExt,
+    sync::Mutex,
+};
+
+/// One authenticated write, as a line of the audit log
+#[derive(Serialize, Deserialize)]
+pub struct AuditEntry {
+    /// Seconds since the unix epoch
+    pub timestamp: u64,
+    pub username: String,
+    /// Name of the API key that was used
+    pub key: String,
+    pub ip: String,
+    /// `publish`, `republish`, `unpublish`, `restore`, `restore_trashed`,
+    /// `purge` or `put_file`
+    pub action: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+    /// SHA-256 of the markdown or file written, or of the post removed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+}
+
+impl AuditEntry {
+    pub fn new(auth: &Auth, ip: String, action: &str) -> Self {
+        Self {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            username: auth.username.clone(),
+            key: auth.key_name.clone(),
+            ip,
+            action: action.to_string(),
+            id: None,
+            file: None,
+            hash: None,
+        }
+    }
+}
+
+/// Each user's log is rotated once it reaches this size, keeping one older log
+const MAX_LOG_BYTES: u64 = 1024 * 1024;
+
+/// Append-only JSON-lines logs, one per user at
+/// `<data_dir>/.audit/<username>.log`
+#[derive(Clone)]
+pub struct AuditLog {
+    dir: PathBuf,
+    /// Keeps concurrent entries from interleaving
+    lock: Arc<Mutex<()>>,
+}
+
+fn internal(e: impl ToString) -> AppError {
+    AppError::Internal(e.to_string())
+}
+
+/// The contents of a log, which may not have been written yet
+async fn read_log(path: &Path) -> Result<String> {
+    match fs::read_to_string(path).await {
+        Ok(content) => Ok(content),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(String::new()),
+        Err(e) => Err(internal(e)),
+    }
+}
+
+impl AuditLog {
+    pub fn new(data_dir: &Path) -> Self {
+        Self {
+            dir: data_dir.join(".audit"),
+            lock: Arc::new(Mutex::new(())),
+        }
+    }
+
+    /// `username`'s current log, or the older one it was rotated to
+    fn path(&self, username: &str, rotated: bool) -> PathBuf {
+        let suffix = if rotated { ".1" } else { "" };
+        self.dir.join(format!("{}.log{}", username, suffix))
+    }
+
+    async fn append(&self, entry: &AuditEntry) -> Result<()> {
+        let mut line = serde_json::to_string(entry).map_err(internal)?;
+        line.push('\n');
+
+        let _guard = self.lock.lock().await;
+        fs::create_dir_all(&self.dir).await.map_err(internal)?;
+        let path = self.path(&entry.username, false);
+        if fs::metadata(&path)
+            .await
+            .is_ok_and(|metadata| metadata.len() >= MAX_LOG_BYTES)
+        {
+            fs::rename(&path, self.path(&entry.username, true))
+                .await
+                .map_err(internal)?;
+        }
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .await
+            .map_err(internal)?;
+        file.write_all(line.as_bytes()).await.map_err(internal)?;
+        file.flush().await.map_err(internal)
+    }
+
+    /// Appends an entry; the write it records has already happened, so a
+    /// failure is logged rather than returned
+    pub async fn record(&self, entry: AuditEntry) {
+        if let Err(e) = self.append(&entry).await {
+            eprintln!("Failed to write audit log entry: {}", e);
+        }
+    }
+
+    /// A page of `username`'s entries, newest first, and whether there are
+    /// older ones
+    pub async fn entries(
+        &self,
+        username: &str,
+        offset: usize,
+        limit: usize,
+    ) -> Result<(Vec<AuditEntry>, bool)> {
+        let current = read_log(&self.path(username, false)).await?;
+        let rotated = read_log(&self.path(username, true)).await?;
+
+        let mut entries = current
+            .lines()
+            .rev()
+            .chain(rotated.lines().rev())
+            .filter_map(|line| serde_json::from_str::<AuditEntry>(line).ok())
+            .skip(offset);
+        let page: Vec<AuditEntry> = entries.by_ref().take(limit).collect();
+        let more = entries.next().is_some();
+        Ok((page, more))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+
+    fn entry(username: &str, id: &str) -> AuditEntry {
+        let auth = Auth {
+            username: username.to_string(),
+            key_name: "default".to_string(),
+            scopes: BTreeSet::new(),
+        };
+        AuditEntry {
+            id: Some(id.to_string()),
+            ..AuditEntry::new(&auth, "127.0.0.1".to_string(), "publish")
+        }
+    }
+
+    fn ids(entries: &[AuditEntry]) -> Vec<&str> {
+        entries
+            .iter()
+            .map(|entry| entry.id.as_deref().unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn pages_each_users_entries_newest_first() {
+        let dir = std::env::temp_dir().join(format!("dollpublish-audit-{}", uuid::Uuid::new_v4()));
+        let log = AuditLog::new(&dir);
+        assert!(log.entries("doll", 0, 10).await.unwrap().0.is_empty());
+
+        for id in ["a", "b", "c"] {
+            log.record(entry("doll", id)).await;
+        }
+        log.record(entry("moth", "m")).await;
+
+        let (page, more) = log.entries("doll", 0, 2).await.unwrap();
+        assert_eq!(ids(&page), ["c", "b"]);
+        assert!(more);
+        let (page, more) = log.entries("doll", 2, 2).await.unwrap();
+        assert_eq!(ids(&page), ["a"]);
+        assert!(!more);
+        let (page, more) = log.entries("doll", 1, 2).await.unwrap();
+        assert_eq!(ids(&page), ["b", "a"]);
+        assert!(!more);
+
+        let (page, _) = log.entries("moth", 0, 10).await.unwrap();
+        assert_eq!(ids(&page), ["m"]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn rotates_full_logs() {
+        let dir = std::env::temp_dir().join(format!("dollpublish-audit-{}", uuid::Uuid::new_v4()));
+        let log = AuditLog::new(&dir);
+        log.record(entry("doll", "old")).await;
+        // Pad the log out to its limit with blank lines
+        let path = log.path("doll", false);
+        let length = std::fs::metadata(&path).unwrap().len();
+        let mut content = std::fs::read_to_string(&path).unwrap();
+        content.push_str(&"\n".repeat((MAX_LOG_BYTES - length) as usize));
+        std::fs::write(&path, content).unwrap();
+
+        log.record(entry("doll", "new")).await;
+        assert!(std::fs::metadata(&path).unwrap().len() < 1024);
+        let (page, more) = log.entries("doll", 0, 10).await.unwrap();
+        assert_eq!(ids(&page), ["new", "old"]);
+        assert!(!more);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
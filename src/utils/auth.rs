@@ -1,10 +1,123 @@
 use crate::error::{AppError, Result};
-use crate::models::user::Users;
-use axum::http::HeaderMap;
-use std::sync::Arc;
-use tokio::sync::Mutex;
+use crate::models::user::Scope;
+use crate::utils::session::{Session, CSRF_HEADER};
+use crate::AppState;
+use axum::http::{header::AUTHORIZATION, HeaderMap};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use std::collections::BTreeSet;
+use subtle::ConstantTimeEq;
 
-pub async fn authenticate(headers: &HeaderMap, users: &Arc<Mutex<Users>>) -> Result<String> {
+/// The user an API key belongs to and what the key may do
+pub struct Auth {
+    pub username: String,
+    /// Name of the key that was used
+    pub key_name: String,
+    pub scopes: BTreeSet<Scope>,
+}
+
+impl Auth {
+    /// Returns the username if the key was granted `scope`
+    pub fn require(&self, scope: Scope) -> Result<String> {
+        if !self.scopes.contains(&scope) {
+            return Err(AppError::Forbidden(format!(
+                "This API key is not scoped for {}",
+                scope
+            )));
+        }
+        Ok(self.username.clone())
+    }
+
+    /// Returns the username if the key was granted every scope, as needed to
+    /// manage keys
+    pub fn require_all(self) -> Result<String> {
+        if self.scopes != Scope::all() {
+            return Err(AppError::Forbidden(
+                "Managing keys needs a key with every scope".to_string(),
+            ));
+        }
+        Ok(self.username)
+    }
+}
+
+/// Credentials from a standard `Authorization` header
+enum Authorization {
+    Bearer(String),
+    /// A username and the key that must belong to it
+    Basic(String, String),
+}
+
+/// Parses `Authorization: Bearer <key>` or `Authorization: Basic
+/// <base64 of username:key>`; other schemes are ignored
+fn authorization(headers: &HeaderMap) -> Option<Authorization> {
+    let value = headers.get(AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, credentials) = value.trim().split_once(' ')?;
+    let credentials = credentials.trim();
+
+    if scheme.eq_ignore_ascii_case("bearer") {
+        return Some(Authorization::Bearer(credentials.to_string()));
+    }
+    if scheme.eq_ignore_ascii_case("basic") {
+        let decoded = BASE64.decode(credentials).ok()?;
+        let decoded = String::from_utf8(decoded).ok()?;
+        let (username, key) = decoded.split_once(':')?;
+        return Some(Authorization::Basic(username.to_string(), key.to_string()));
+    }
+    None
+}
+
+/// Resolves a browser session, which must repeat its CSRF token in a header
+async fn authenticate_session(headers: &HeaderMap, state: &AppState) -> Result<Auth> {
+    let (session, auth) = session_auth(headers, state).await?;
+    check_csrf(
+        &session,
+        headers.get(CSRF_HEADER).and_then(|h| h.to_str().ok()),
+    )?;
+    Ok(auth)
+}
+
+/// Resolves the browser session of a request without checking its CSRF
+/// token, which callers must do for anything but reads
+pub async fn session_auth(headers: &HeaderMap, state: &AppState) -> Result<(Session, Auth)> {
+    let session = state
+        .sessions
+        .current(headers)
+        .ok_or(AppError::AuthenticationError)?;
+
+    let scopes = state
+        .users
+        .read()
+        .await
+        .session_scopes(&session)
+        .ok_or(AppError::AuthenticationError)?;
+    let auth = Auth {
+        username: session.username.clone(),
+        key_name: session
+            .key
+            .clone()
+            .unwrap_or_else(|| "password".to_string()),
+        scopes,
+    };
+    Ok((session, auth))
+}
+
+/// Rejects a request made with a session unless it carries that session's
+/// CSRF token
+pub fn check_csrf(session: &Session, token: Option<&str>) -> Result<()> {
+    let valid =
+        token.is_some_and(|token| bool::from(token.as_bytes().ct_eq(session.csrf.as_bytes())));
+    if !valid {
+        return Err(AppError::Forbidden(
+            "Missing or invalid CSRF token".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Accepts `Authorization: Bearer`, `Authorization: Basic` with the username
+/// the key belongs to, the key in either the `api-key` or `api-secret` header
+/// as the MoonServer plugin sends it, or a browser session
+pub async fn authenticate(headers: &HeaderMap, state: &AppState) -> Result<Auth> {
+    let users = &state.users;
     let api_key = headers
         .get("api-key")
         .and_then(|h| h.to_str().ok())
@@ -15,13 +128,125 @@ pub async fn authenticate(headers: &HeaderMap, users: &Arc<Mutex<Users>>) -> Res
         .and_then(|h| h.to_str().ok())
         .unwrap_or("");
 
-    if api_key.is_empty() && api_secret.is_empty() {
+    let key = {
+        let users = users.read().await;
+        let key = match authorization(headers) {
+            Some(Authorization::Bearer(key)) => users.verify_credentials(&key, ""),
+            Some(Authorization::Basic(username, key)) => users
+                .verify_credentials(&key, "")
+                .filter(|found| found.username == username),
+            None if api_key.is_empty() && api_secret.is_empty() => {
+                drop(users);
+                return authenticate_session(headers, state).await;
+            }
+            None => users.verify_credentials(api_key, api_secret),
+        }
+        .ok_or(AppError::InvalidCredentials)?;
+        users.note_use(&key.username, &key.name);
+        key
+    };
+
+    Ok(Auth {
+        username: key.username,
+        key_name: key.name,
+        scopes: key.scopes,
+    })
+}
+
+/// Like [`authenticate`], but a browser session needs no CSRF token, which
+/// is only safe for requests that change nothing
+pub async fn authenticate_read(headers: &HeaderMap, state: &AppState) -> Result<Auth> {
+    let has_key = authorization(headers).is_some()
+        || ["api-key", "api-secret"]
+            .iter()
+            .any(|name| headers.get(*name).is_some_and(|value| !value.is_empty()));
+    if has_key {
+        return authenticate(headers, state).await;
+    }
+    Ok(session_auth(headers, state).await?.1)
+}
+
+/// Checks the `admin-key` header against `MOON_ADMIN_KEY`; without one
+/// configured the admin API does not exist
+pub fn authenticate_admin(headers: &HeaderMap, admin_key: Option<&str>) -> Result<()> {
+    let admin_key = admin_key.ok_or(AppError::NotFound)?;
+    let given = headers
+        .get("admin-key")
+        .and_then(|h| h.to_str().ok())
+        .unwrap_or("");
+
+    if given.is_empty() {
         return Err(AppError::AuthenticationError);
     }
+    if !bool::from(given.as_bytes().ct_eq(admin_key.as_bytes())) {
+        return Err(AppError::InvalidCredentials);
+    }
+    Ok(())
+}
 
-    let mut users = users.lock().await;
-    users
-        .verify_credentials(api_key, api_secret)
-        .await
-        .ok_or(AppError::AuthenticationError)
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestServer;
+    use axum::http::HeaderValue;
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(*name, HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    fn basic(username: &str, key: &str) -> String {
+        format!("Basic {}", BASE64.encode(format!("{}:{}", username, key)))
+    }
+
+    #[test]
+    fn parses_authorization_headers() {
+        let parsed = authorization(&headers(&[("authorization", "bearer  dp_abc ")]));
+        assert!(matches!(parsed, Some(Authorization::Bearer(key)) if key == "dp_abc"));
+
+        let parsed = authorization(&headers(&[("authorization", &basic("doll", "dp:abc"))]));
+        assert!(matches!(
+            parsed,
+            Some(Authorization::Basic(username, key)) if username == "doll" && key == "dp:abc"
+        ));
+
+        for value in ["Basic not*base64", "Basic ZG9sbA==", "Digest abc", "dp_abc"] {
+            assert!(authorization(&headers(&[("authorization", value)])).is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn authenticates_keys_however_they_are_sent() {
+        let server = TestServer::new().await;
+        let key = server.key.as_str();
+        let accepted = [
+            headers(&[("authorization", &format!("Bearer {}", key))]),
+            headers(&[("authorization", &basic("doll", key))]),
+            headers(&[("api-key", key)]),
+            headers(&[("api-secret", key)]),
+        ];
+        for headers in accepted {
+            let auth = authenticate(&headers, &server.state).await.unwrap();
+            assert_eq!(auth.username, "doll");
+            assert_eq!(auth.key_name, "default");
+        }
+
+        let rejected = [
+            // The key is valid, but belongs to someone else
+            headers(&[("authorization", &basic("moth", key))]),
+            headers(&[("authorization", "Basic not*base64")]),
+            headers(&[("authorization", "Bearer dp_0000")]),
+            headers(&[("api-key", "dp_0000")]),
+            headers(&[]),
+        ];
+        for headers in rejected {
+            assert!(matches!(
+                authenticate(&headers, &server.state).await,
+                Err(AppError::AuthenticationError | AppError::InvalidCredentials)
+            ));
+        }
+    }
 }
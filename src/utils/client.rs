@@ -0,0 +1,43 @@
+use crate::AppState;
+use async_trait::async_trait;
+use axum::{
+    extract::{ConnectInfo, FromRequestParts},
+    http::{request::Parts, HeaderMap},
+};
+use std::{convert::Infallible, net::SocketAddr};
+
+/// The address a request came from, taken from the last `X-Forwarded-For`
+/// entry when running behind a trusted proxy
+pub fn client_address(headers: &HeaderMap, addr: SocketAddr, trust_proxy: bool) -> String {
+    if trust_proxy {
+        let forwarded = headers
+            .get("x-forwarded-for")
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| h.rsplit(',').next())
+            .map(str::trim)
+            .filter(|h| !h.is_empty());
+        if let Some(forwarded) = forwarded {
+            return forwarded.to_string();
+        }
+    }
+    addr.ip().to_string()
+}
+
+/// Extracts the [`client_address`] of a request
+pub struct ClientAddress(pub String);
+
+#[async_trait]
+impl FromRequestParts<AppState> for ClientAddress {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let address = match parts.extensions.get::<ConnectInfo<SocketAddr>>() {
+            Some(ConnectInfo(addr)) => client_address(&parts.headers, *addr, state.trust_proxy),
+            None => "unknown".to_string(),
+        };
+        Ok(ClientAddress(address))
+    }
+}
@@ -0,0 +1,13 @@
+use crate::storage::Revision;
+use similar::TextDiff;
+
+/// Renders the content change between two revisions as a unified diff
+pub fn unified_diff(from: &Revision, to: &Revision) -> String {
+    TextDiff::from_lines(&from.content, &to.content)
+        .unified_diff()
+        .header(
+            &format!("revision {}", from.number),
+            &format!("revision {}", to.number),
+        )
+        .to_string()
+}
@@ -1,3 +1,10 @@
+pub mod audit;
 pub mod auth;
+pub mod client;
+pub mod diff;
 pub mod id_generator;
+pub mod rate_limit;
+pub mod session;
 pub mod template;
+pub mod time;
+pub mod uploads;
@@ -0,0 +1,349 @@
+use crate::{error::AppError, utils::client::client_address};
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::HeaderMap,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Headers that carry credentials, each of which is limited on its own
+const CREDENTIAL_HEADERS: [&str; 4] = ["authorization", "api-key", "api-secret", "admin-key"];
+
+#[derive(Clone)]
+pub struct RateLimitConfig {
+    /// Requests per minute allowed per client address and per key; `0`
+    /// disables the limit
+    pub per_minute: u32,
+    /// How many requests may be made at once before the limit applies
+    pub burst: u32,
+    /// Failed authentications before a client or key is locked out; `0`
+    /// disables lockout
+    pub max_failures: u32,
+    /// How long a lockout lasts, and how long failures are remembered
+    pub lockout: Duration,
+    /// Take the client address from the last `X-Forwarded-For` entry
+    pub trust_proxy: bool,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    failures: u32,
+    last_failure: Instant,
+    locked_until: Option<Instant>,
+}
+
+impl Bucket {
+    fn new(burst: u32, now: Instant) -> Self {
+        Self {
+            tokens: f64::from(burst),
+            updated: now,
+            failures: 0,
+            last_failure: now,
+            locked_until: None,
+        }
+    }
+}
+
+/// Marks a response to a request whose credentials were wrong, which
+/// [`rate_limit`] counts towards locking out the client and key
+#[derive(Clone, Copy)]
+pub struct FailedAuthentication;
+
+/// Token buckets and failure counts per client address and per key
+#[derive(Clone)]
+pub struct RateLimiter {
+    config: Arc<RateLimitConfig>,
+    buckets: Arc<Mutex<HashMap<String, Bucket>>>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self {
+            config: Arc::new(config),
+            buckets: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    fn rate(&self) -> f64 {
+        f64::from(self.config.per_minute) / 60.0
+    }
+
+    /// Takes a token from each bucket, or returns how long to wait if any of
+    /// them is empty or locked out
+    fn check(&self, ids: &[String]) -> Result<(), Duration> {
+        let now = Instant::now();
+        let rate = self.rate();
+        let burst = f64::from(self.config.burst.max(1));
+        let mut buckets = self.buckets.lock().unwrap();
+
+        let mut wait = Duration::ZERO;
+        for id in ids {
+            let bucket = buckets
+                .entry(id.clone())
+                .or_insert_with(|| Bucket::new(self.config.burst.max(1), now));
+
+            if let Some(until) = bucket.locked_until {
+                if until > now {
+                    wait = wait.max(until - now);
+                    continue;
+                }
+                bucket.locked_until = None;
+            }
+
+            if self.config.per_minute > 0 {
+                let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+                bucket.tokens = (bucket.tokens + elapsed * rate).min(burst);
+                bucket.updated = now;
+                if bucket.tokens < 1.0 {
+                    wait = wait.max(Duration::from_secs_f64((1.0 - bucket.tokens) / rate));
+                }
+            }
+        }
+
+        if !wait.is_zero() {
+            return Err(wait);
+        }
+        if self.config.per_minute > 0 {
+            for id in ids {
+                if let Some(bucket) = buckets.get_mut(id) {
+                    bucket.tokens -= 1.0;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Counts a failed authentication, locking out each id that reached the
+    /// failure limit
+    fn record_failure(&self, ids: &[String]) {
+        if self.config.max_failures == 0 {
+            return;
+        }
+
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        for id in ids {
+            let bucket = buckets
+                .entry(id.clone())
+                .or_insert_with(|| Bucket::new(self.config.burst.max(1), now));
+
+            if now.duration_since(bucket.last_failure) > self.config.lockout {
+                bucket.failures = 0;
+            }
+            bucket.failures += 1;
+            bucket.last_failure = now;
+
+            if bucket.failures >= self.config.max_failures {
+                bucket.failures = 0;
+                bucket.locked_until = Some(now + self.config.lockout);
+            }
+        }
+    }
+
+    /// Drops buckets that are full and hold no failures or lockout
+    fn prune(&self) {
+        let now = Instant::now();
+        let burst = f64::from(self.config.burst.max(1));
+        let rate = self.rate();
+        let lockout = self.config.lockout;
+
+        self.buckets.lock().unwrap().retain(|_, bucket| {
+            let tokens = bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate;
+            let locked = bucket.locked_until.is_some_and(|until| until > now);
+            let failing = bucket.failures > 0 && now.duration_since(bucket.last_failure) <= lockout;
+            locked || failing || (self.config.per_minute > 0 && tokens < burst)
+        });
+    }
+
+    /// Prunes idle buckets every minute
+    pub fn spawn_prune(&self) {
+        let limiter = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(60));
+            loop {
+                interval.tick().await;
+                limiter.prune();
+            }
+        });
+    }
+}
+
+/// The buckets a request is counted against: its client address and every
+/// credential it presents, hashed so that guesses are not kept in memory
+fn request_ids(limiter: &RateLimiter, headers: &HeaderMap, addr: SocketAddr) -> Vec<String> {
+    let address = client_address(headers, addr, limiter.config.trust_proxy);
+    let mut ids = vec![format!("ip:{}", address)];
+    for name in CREDENTIAL_HEADERS {
+        let Some(value) = headers.get(name).filter(|v| !v.is_empty()) else {
+            continue;
+        };
+        let id = format!("key:{}", hex::encode(Sha256::digest(value.as_bytes())));
+        if !ids.contains(&id) {
+            ids.push(id);
+        }
+    }
+    ids
+}
+
+/// Middleware that rejects requests over the rate limit or from locked out
+/// clients and keys with 429, and counts failed authentications
+pub async fn rate_limit(
+    State(limiter): State<RateLimiter>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let ids = request_ids(&limiter, request.headers(), addr);
+    if let Err(wait) = limiter.check(&ids) {
+        let seconds = wait.as_secs_f64().ceil().max(1.0) as u64;
+        return AppError::TooManyRequests(seconds).into_response();
+    }
+
+    let response = next.run(request).await;
+    if response
+        .extensions()
+        .get::<FailedAuthentication>()
+        .is_some()
+    {
+        limiter.record_failure(&ids);
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::AppError;
+    use axum::{
+        body::Body,
+        http::{header::RETRY_AFTER, StatusCode},
+        middleware,
+        routing::get,
+        Router,
+    };
+    use tower::ServiceExt;
+
+    fn limiter(per_minute: u32, burst: u32, max_failures: u32) -> RateLimiter {
+        RateLimiter::new(RateLimitConfig {
+            per_minute,
+            burst,
+            max_failures,
+            lockout: Duration::from_secs(60),
+            trust_proxy: false,
+        })
+    }
+
+    fn ids(ids: &[&str]) -> Vec<String> {
+        ids.iter().map(|id| id.to_string()).collect()
+    }
+
+    #[test]
+    fn runs_out_after_the_burst() {
+        let limiter = limiter(60, 2, 0);
+        let client = ids(&["ip:1"]);
+        assert!(limiter.check(&client).is_ok());
+        assert!(limiter.check(&client).is_ok());
+        let wait = limiter.check(&client).unwrap_err();
+        assert!(wait > Duration::from_millis(900) && wait <= Duration::from_secs(1));
+
+        // Other clients have buckets of their own, but sharing a key with an
+        // empty bucket is enough to be limited
+        assert!(limiter.check(&ids(&["ip:2"])).is_ok());
+        assert!(limiter.check(&ids(&["ip:2", "ip:1"])).is_err());
+    }
+
+    #[test]
+    fn locks_out_after_too_many_failures() {
+        let limiter = limiter(0, 1, 3);
+        let client = ids(&["ip:1", "key:a"]);
+        limiter.record_failure(&client);
+        limiter.record_failure(&client);
+        assert!(limiter.check(&client).is_ok());
+
+        limiter.record_failure(&client);
+        let wait = limiter.check(&client).unwrap_err();
+        assert!(wait > Duration::from_secs(59) && wait <= Duration::from_secs(60));
+        // Both the address and the key are locked out
+        assert!(limiter.check(&ids(&["key:a"])).is_err());
+        assert!(limiter.check(&ids(&["ip:1"])).is_err());
+    }
+
+    #[test]
+    fn prunes_idle_buckets() {
+        let limiter = limiter(60, 1, 3);
+        limiter.check(&ids(&["ip:used", "ip:idle"])).unwrap();
+        limiter.record_failure(&ids(&["ip:failing"]));
+        {
+            let mut buckets = limiter.buckets.lock().unwrap();
+            let idle = buckets.get_mut("ip:idle").unwrap();
+            // Long enough ago to have refilled
+            idle.updated = Instant::now() - Duration::from_secs(5);
+        }
+
+        limiter.prune();
+        let buckets = limiter.buckets.lock().unwrap();
+        let mut kept: Vec<&String> = buckets.keys().collect();
+        kept.sort();
+        assert_eq!(kept, ["ip:failing", "ip:used"]);
+    }
+
+    async fn send(app: &Router, uri: &str) -> axum::response::Response {
+        let mut request = Request::get(uri)
+            .header("api-key", "dp_guess")
+            .body(Body::empty())
+            .unwrap();
+        request
+            .extensions_mut()
+            .insert(ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 4000))));
+        app.clone().oneshot(request).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn limits_requests_through_the_middleware() {
+        let app = Router::new()
+            .route("/", get(|| async { "ok" }))
+            .layer(middleware::from_fn_with_state(limiter(1, 1, 0), rate_limit));
+
+        assert_eq!(send(&app, "/").await.status(), StatusCode::OK);
+        let response = send(&app, "/").await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[RETRY_AFTER], "60");
+    }
+
+    #[tokio::test]
+    async fn counts_only_rejected_credentials_as_failures() {
+        let app = Router::new()
+            .route("/denied", get(|| async { StatusCode::UNAUTHORIZED }))
+            .route(
+                "/wrong",
+                get(|| async { AppError::InvalidCredentials.into_response() }),
+            )
+            .layer(middleware::from_fn_with_state(limiter(0, 1, 2), rate_limit));
+
+        for _ in 0..3 {
+            assert_eq!(
+                send(&app, "/denied").await.status(),
+                StatusCode::UNAUTHORIZED
+            );
+        }
+        assert_eq!(
+            send(&app, "/wrong").await.status(),
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            send(&app, "/wrong").await.status(),
+            StatusCode::UNAUTHORIZED
+        );
+        let response = send(&app, "/denied").await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[RETRY_AFTER], "60");
+    }
+}
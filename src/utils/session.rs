@@ -0,0 +1,240 @@
+use crate::{
+    error::{AppError, Result},
+    models::user::VerifiedKey,
+};
+use axum::http::{header::COOKIE, HeaderMap};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD as BASE64_URL, Engine as _};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::{
+    path::Path,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+pub const SESSION_COOKIE: &str = "dp_session";
+
+/// Header that requests authenticated by the session cookie must repeat the
+/// session's CSRF token in
+pub const CSRF_HEADER: &str = "x-csrf-token";
+
+/// A browser login, kept in a signed cookie
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Session {
+    pub username: String,
+    /// The key logged in with, or `None` for a password login
+    pub key: Option<String>,
+    /// [`VerifiedKey::id`] of the key logged in with, so that the session
+    /// ends when the key is rotated
+    #[serde(default)]
+    pub key_id: Option<String>,
+    /// Seconds since the unix epoch
+    pub expires: u64,
+    pub csrf: String,
+    /// The user's session epoch when the session started
+    #[serde(default)]
+    pub epoch: u64,
+}
+
+/// Signs and checks session cookies with a secret kept in
+/// `<data_dir>/.session_secret`, so sessions survive restarts
+#[derive(Clone)]
+pub struct Sessions {
+    secret: Arc<Vec<u8>>,
+    lifetime: Duration,
+    secure: bool,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn random_hex(len: usize) -> String {
+    let mut bytes = vec![0u8; len];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+impl Sessions {
+    pub fn load_or_create(data_dir: &Path, lifetime: Duration, secure: bool) -> Result<Self> {
+        let path = data_dir.join(".session_secret");
+        let secret = match std::fs::read_to_string(&path) {
+            Ok(secret) => {
+                hex::decode(secret.trim()).map_err(|e| AppError::Internal(e.to_string()))?
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let secret = random_hex(32);
+                write_private(&path, &secret)?;
+                hex::decode(secret).map_err(|e| AppError::Internal(e.to_string()))?
+            }
+            Err(e) => return Err(AppError::Internal(e.to_string())),
+        };
+
+        Ok(Self {
+            secret: Arc::new(secret),
+            lifetime,
+            secure,
+        })
+    }
+
+    fn mac(&self) -> Hmac<Sha256> {
+        Hmac::<Sha256>::new_from_slice(&self.secret).expect("HMAC accepts any key length")
+    }
+
+    /// Starts a session with `key`, or with a password when `None`,
+    /// returning it with the `Set-Cookie` value for it
+    pub fn create(
+        &self,
+        username: &str,
+        key: Option<&VerifiedKey>,
+        epoch: u64,
+    ) -> (Session, String) {
+        let session = Session {
+            username: username.to_string(),
+            key: key.map(|key| key.name.clone()),
+            key_id: key.map(|key| key.id.clone()),
+            expires: now() + self.lifetime.as_secs(),
+            csrf: random_hex(16),
+            epoch,
+        };
+
+        let payload = BASE64_URL.encode(serde_json::to_vec(&session).unwrap());
+        let mut mac = self.mac();
+        mac.update(payload.as_bytes());
+        let signature = hex::encode(mac.finalize().into_bytes());
+
+        let cookie = format!(
+            "{}={}.{}; Path=/; Max-Age={}; HttpOnly; SameSite=Lax{}",
+            SESSION_COOKIE,
+            payload,
+            signature,
+            self.lifetime.as_secs(),
+            if self.secure { "; Secure" } else { "" }
+        );
+        (session, cookie)
+    }
+
+    /// The `Set-Cookie` value that ends a session
+    pub fn clear_cookie(&self) -> String {
+        format!(
+            "{}=; Path=/; Max-Age=0; HttpOnly; SameSite=Lax{}",
+            SESSION_COOKIE,
+            if self.secure { "; Secure" } else { "" }
+        )
+    }
+
+    /// The unexpired session in the request's cookie, if its signature holds
+    pub fn current(&self, headers: &HeaderMap) -> Option<Session> {
+        let value = headers
+            .get_all(COOKIE)
+            .iter()
+            .filter_map(|h| h.to_str().ok())
+            .flat_map(|h| h.split(';'))
+            .filter_map(|pair| pair.trim().split_once('='))
+            .find(|(name, _)| *name == SESSION_COOKIE)
+            .map(|(_, value)| value)?;
+
+        let (payload, signature) = value.split_once('.')?;
+        let mut mac = self.mac();
+        mac.update(payload.as_bytes());
+        mac.verify_slice(&hex::decode(signature).ok()?).ok()?;
+
+        let session: Session = serde_json::from_slice(&BASE64_URL.decode(payload).ok()?).ok()?;
+        (session.expires > now()).then_some(session)
+    }
+}
+
+/// Creates a file readable only by its owner where the platform allows it
+fn write_private(path: &Path, contents: &str) -> Result<()> {
+    use std::io::Write;
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    let mut file = options
+        .open(path)
+        .map_err(|e| AppError::Internal(e.to_string()))?;
+    file.write_all(contents.as_bytes())
+        .map_err(|e| AppError::Internal(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::auth::check_csrf;
+    use axum::http::HeaderValue;
+
+    fn sessions(dir: &Path, lifetime: Duration) -> Sessions {
+        Sessions::load_or_create(dir, lifetime, false).unwrap()
+    }
+
+    /// The request headers a browser sends back for a `Set-Cookie` value
+    fn cookie_headers(set_cookie: &str) -> HeaderMap {
+        let value = set_cookie.split(';').next().unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(COOKIE, HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    #[test]
+    fn rejects_tampered_and_expired_sessions() {
+        let dir =
+            std::env::temp_dir().join(format!("dollpublish-sessions-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let sessions = sessions(&dir, Duration::from_secs(3600));
+        let (_, cookie) = sessions.create("doll", None, 7);
+        let found = sessions.current(&cookie_headers(&cookie)).unwrap();
+        assert_eq!(found.username, "doll");
+        assert_eq!(found.epoch, 7);
+
+        // Another user's name under the same signature
+        let value = cookie.split(';').next().unwrap();
+        let (payload, signature) = value.split_once('.').unwrap();
+        let mut session: Session = serde_json::from_slice(
+            &BASE64_URL
+                .decode(payload.trim_start_matches("dp_session="))
+                .unwrap(),
+        )
+        .unwrap();
+        session.username = "moth".to_string();
+        let forged = BASE64_URL.encode(serde_json::to_vec(&session).unwrap());
+        let tampered = format!("{}={}.{}", SESSION_COOKIE, forged, signature);
+        assert!(sessions.current(&cookie_headers(&tampered)).is_none());
+
+        let mut signature = signature.to_string();
+        let last = if signature.ends_with('0') { "1" } else { "0" };
+        signature.replace_range(signature.len() - 1.., last);
+        let tampered = format!("{}.{}", payload, signature);
+        assert!(sessions.current(&cookie_headers(&tampered)).is_none());
+
+        // The secret is kept, so sessions still hold after a restart
+        let restarted = Sessions::load_or_create(&dir, Duration::ZERO, false).unwrap();
+        assert!(restarted.current(&cookie_headers(&cookie)).is_some());
+        let (_, expired) = restarted.create("doll", None, 7);
+        assert!(restarted.current(&cookie_headers(&expired)).is_none());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn checks_csrf_tokens() {
+        let dir =
+            std::env::temp_dir().join(format!("dollpublish-sessions-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (session, _) = sessions(&dir, Duration::from_secs(3600)).create("doll", None, 0);
+
+        assert!(check_csrf(&session, Some(&session.csrf)).is_ok());
+        assert!(check_csrf(&session, None).is_err());
+        assert!(check_csrf(&session, Some("")).is_err());
+        assert!(check_csrf(&session, Some(&session.csrf[1..])).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
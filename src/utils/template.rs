@@ -1,7 +1,7 @@
 use crate::models::post::Post;
 use handlebars::Handlebars;
 use serde::Serialize;
-use std::{collections::HashMap, fs, path::PathBuf};
+use std::{collections::HashMap, fs, path::Path};
 
 const DEFAULT_TEMPLATE: &str = r#"<!DOCTYPE html>
 <html lang="en">
@@ -9,11 +9,29 @@ const DEFAULT_TEMPLATE: &str = r#"<!DOCTYPE html>
     <meta charset="UTF-8">
     <meta name="viewport" content="width=device-width, initial-scale=1.0">
     <link rel="stylesheet" href="https://cdn.jsdelivr.net/npm/sakura.css/css/sakura.css">
+    <link rel="stylesheet" href="https://cdn.jsdelivr.net/gh/carloskiki/pulldown-latex@0.8.0/styles.min.css">
     <title>{{author}} - {{title}}</title>
     <meta name="author" content="{{author}}">
     {{#if description}}
     <meta name="description" content="{{description}}">
     {{/if}}
+    <style>
+        .wikilink-unresolved { opacity: 0.6; text-decoration: underline dotted; }
+        .transclusion { border-left: 3px solid #ccc; padding-left: 1em; margin: 1em 0; }
+        .transclusion-source { font-size: 0.85em; }
+        .callout { --callout-color: #448aff; border-left: 4px solid var(--callout-color); border-radius: 4px; background: color-mix(in srgb, var(--callout-color) 10%, transparent); padding: 0.5em 1em; margin: 1em 0; }
+        .callout-title { font-weight: bold; color: var(--callout-color); }
+        .callout summary { cursor: pointer; }
+        .callout-content > :last-child { margin-bottom: 0; }
+        .callout-abstract, .callout-info, .callout-todo { --callout-color: #00b0ff; }
+        .callout-tip { --callout-color: #00bfa5; }
+        .callout-success { --callout-color: #00c853; }
+        .callout-question { --callout-color: #64dd17; }
+        .callout-warning { --callout-color: #ff9100; }
+        .callout-failure, .callout-danger, .callout-bug { --callout-color: #ff5252; }
+        .callout-example { --callout-color: #7c4dff; }
+        .callout-quote { --callout-color: #9e9e9e; }
+    </style>
 </head>
 <body>
     <article>
@@ -51,16 +69,31 @@ impl Templates {
         }
     }
 
-    pub fn render(&self, data_dir: &PathBuf, username: &str, post: &Post, content: &str) -> String {
-        // Check for user template
-        let template = if let Ok(user_template) =
-            fs::read_to_string(data_dir.join(username).join("template.html"))
-        {
-            user_template
-        } else {
-            DEFAULT_TEMPLATE.to_string()
-        };
+    /// The user's `template.html`, or the default template if they have none
+    pub fn user_template(data_dir: &Path, username: &str) -> String {
+        fs::read_to_string(data_dir.join(username).join("template.html"))
+            .unwrap_or_else(|_| DEFAULT_TEMPLATE.to_string())
+    }
+
+    pub fn render(&self, data_dir: &Path, username: &str, post: &Post, content: &str) -> String {
+        let template = Self::user_template(data_dir, username);
+        self.render_template(&template, username, post, content)
+            .unwrap_or_else(|_| {
+                // Fallback to default template if user template fails
+                self.render_template(DEFAULT_TEMPLATE, username, post, content)
+                    .unwrap_or_else(|_| "Template rendering failed".to_string())
+            })
+    }
 
+    /// Renders a post with the given template, returning the error message
+    /// if the template is invalid
+    pub fn render_template(
+        &self,
+        template: &str,
+        username: &str,
+        post: &Post,
+        content: &str,
+    ) -> Result<String, String> {
         // Extract description from metadata if it exists
         let description = post
             .metadata
@@ -78,12 +111,7 @@ impl Templates {
         };
 
         self.engine
-            .render_template(&template, &template_data)
-            .unwrap_or_else(|_| {
-                // Fallback to default template if user template fails
-                self.engine
-                    .render_template(DEFAULT_TEMPLATE, &template_data)
-                    .unwrap_or_else(|_| "Template rendering failed".to_string())
-            })
+            .render_template(template, &template_data)
+            .map_err(|e| e.to_string())
     }
 }
@@ -0,0 +1,57 @@
+//! Conversions between days since the unix epoch and UTC calendar dates,
+//! after Howard Hinnant's `civil_from_days` and `days_from_civil`
+
+/// The `(year, month, day)` that is `days` after 1970-01-01
+pub fn civil_date(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month as u32, day as u32)
+}
+
+/// Days from 1970-01-01 to a date, the inverse of [`civil_date`]
+#[cfg_attr(not(feature = "s3"), allow(dead_code))]
+pub fn days_since_epoch(year: i64, month: u32, day: u32) -> i64 {
+    let (month, day) = (i64::from(month), i64::from(day));
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y.rem_euclid(400);
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_known_dates() {
+        for (days, date) in [
+            (0, (1970, 1, 1)),
+            (-1, (1969, 12, 31)),
+            (11016, (2000, 2, 29)),
+            (11017, (2000, 3, 1)),
+            (19783, (2024, 3, 1)),
+            (47541, (2100, 3, 1)),
+        ] {
+            assert_eq!(civil_date(days), date);
+            assert_eq!(days_since_epoch(date.0, date.1, date.2), days);
+        }
+    }
+
+    #[test]
+    fn round_trips() {
+        for days in (-800_000..800_000).step_by(97) {
+            let (year, month, day) = civil_date(days);
+            assert_eq!(days_since_epoch(year, month, day), days);
+        }
+    }
+}
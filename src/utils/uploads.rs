@@ -0,0 +1,33 @@
+use crate::storage::gc::GRACE_PERIOD;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+/// Blobs each user has uploaded to `/_moon/blobs/` but may not have
+/// published yet. Together with the blobs their posts already reference,
+/// these are the only ones a user is told exist or may publish by hash, so
+/// nobody can find out which files someone else has uploaded.
+#[derive(Clone, Default)]
+pub struct UploadedBlobs {
+    inner: Arc<Mutex<HashMap<String, HashMap<String, Instant>>>>,
+}
+
+impl UploadedBlobs {
+    pub fn record(&self, username: &str, hash: &str) {
+        let mut uploads = self.inner.lock().unwrap();
+        let user = uploads.entry(username.to_string()).or_default();
+        // Blobs nothing references are collected after the grace period
+        user.retain(|_, uploaded| uploaded.elapsed() < GRACE_PERIOD);
+        user.insert(hash.to_string(), Instant::now());
+    }
+
+    pub fn contains(&self, username: &str, hash: &str) -> bool {
+        let uploads = self.inner.lock().unwrap();
+        uploads
+            .get(username)
+            .and_then(|user| user.get(hash))
+            .is_some_and(|uploaded| uploaded.elapsed() < GRACE_PERIOD)
+    }
+}